@@ -0,0 +1,324 @@
+//! A command-line interface to chitchat, for scripting it on machines without
+//! a display. Models, prompt templates and conversations are shared with the
+//! desktop app.
+
+use anyhow::{anyhow, Result};
+use bytesize::ByteSize;
+use chitchat_lib::cancellation::Canceller;
+use chitchat_lib::conversations::{Conversation, Message, Role, Stats};
+use chitchat_lib::models::{get_local_model, ModelManager};
+use chitchat_lib::prompt::Variables;
+use chitchat_lib::{context_file, conversations, models, templates};
+use clap::{Args, Parser, Subcommand};
+use llm::{InferenceResponse, LoadProgress};
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Parser)]
+#[command(
+    name = "chitchat-cli",
+    version,
+    about = "Chat with local LLM models from the terminal"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists the catalog models and the models in ~/.chitchat/models
+    List,
+    /// Downloads a catalog model, picking up where an earlier download left off
+    Download { filename: String },
+    /// Responds to a single prompt and exits
+    Prompt {
+        #[command(flatten)]
+        model: ModelArgs,
+        /// The prompt, read from standard input when left out
+        prompt: Option<String>,
+    },
+    /// Starts an interactive chat. The conversation is saved so that it can
+    /// be picked up in the app later.
+    Chat {
+        #[command(flatten)]
+        model: ModelArgs,
+    },
+}
+
+#[derive(Args)]
+struct ModelArgs {
+    /// The filename of the model, which is downloaded first if it's a catalog
+    /// model that isn't on disk yet
+    #[arg(short, long)]
+    model: String,
+    /// Detected from the model file when left out
+    #[arg(long)]
+    architecture: Option<String>,
+    #[arg(long, default_value_t = 2048)]
+    context_size: usize,
+    #[arg(long)]
+    gpu: bool,
+    /// The name of the prompt template, the model's own by default
+    #[arg(short, long)]
+    template: Option<String>,
+    /// A file whose contents the model should know about
+    #[arg(short = 'f', long = "file")]
+    context_files: Vec<PathBuf>,
+    /// A template variable, as `name=value`
+    #[arg(long = "var", value_parser = parse_variable)]
+    variables: Vec<(String, String)>,
+    #[arg(long)]
+    max_tokens: Option<usize>,
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+fn parse_variable(value: &str) -> Result<(String, String)> {
+    let (name, value) = value
+        .split_once('=')
+        .ok_or(anyhow!("Expected name=value"))?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Ctrl-C stops the response that's being generated, keeping what was said
+/// so far, and quits otherwise.
+#[derive(Default)]
+struct Interrupts {
+    canceller: Canceller,
+    generating: AtomicBool,
+}
+
+impl Interrupts {
+    async fn listen(self: Arc<Self>) {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if !self.generating.load(Ordering::Acquire) {
+                eprintln!();
+                process::exit(130);
+            }
+            self.canceller.cancel();
+        }
+    }
+
+    /// Runs `f` with a canceller that Ctrl-C cancels instead of quitting.
+    fn generate<T>(&self, f: impl FnOnce(&Canceller) -> T) -> T {
+        self.canceller.reset();
+        self.generating.store(true, Ordering::Release);
+        let result = f(&self.canceller);
+        self.generating.store(false, Ordering::Release);
+        result
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let interrupts = Arc::new(Interrupts::default());
+    tokio::spawn(interrupts.clone().listen());
+    match Cli::parse().command {
+        Command::List => list().await,
+        Command::Download { filename } => {
+            download(&filename).await?;
+            Ok(())
+        }
+        Command::Prompt { model, prompt } => {
+            let prompt = match prompt {
+                Some(prompt) => prompt,
+                None => {
+                    let mut prompt = String::new();
+                    io::stdin().read_to_string(&mut prompt)?;
+                    prompt
+                }
+            };
+            let mut manager = start(&model).await?;
+            respond(&mut manager, prompt.trim(), &interrupts)?;
+            conversations::save(&manager.conversation)?;
+            eprintln!("Saved conversation {}", manager.conversation.id);
+            Ok(())
+        }
+        Command::Chat { model } => chat(start(&model).await?, &interrupts),
+    }
+}
+
+async fn list() -> Result<()> {
+    for model in models::get_available_models().await? {
+        let size = model.size.map(|s| ByteSize(s).to_string());
+        println!(
+            "{:<48} {:<10} {}",
+            model.filename,
+            size.as_deref().unwrap_or("-"),
+            model.name
+        );
+    }
+    Ok(())
+}
+
+async fn download(filename: &str) -> Result<PathBuf> {
+    let path = get_local_model(filename, &Canceller::default(), |downloaded, total, _| {
+        eprint!(
+            "\rDownloading {} ({} / {})",
+            filename,
+            ByteSize(downloaded),
+            ByteSize(total)
+        );
+    })
+    .await?;
+    eprintln!();
+    Ok(path)
+}
+
+/// Loads the model and warms it up with the system prompt, the same way the
+/// app does.
+async fn start(args: &ModelArgs) -> Result<ModelManager> {
+    let defaults = models::find_model(&args.model).await?;
+    let template = match &args.template {
+        Some(name) => templates::find(name)?.ok_or(anyhow!("Template {} not found", name))?,
+        None => templates::default_for(defaults.as_ref().and_then(|m| m.template.as_deref()))?,
+    };
+    template.check()?;
+    let context = args
+        .context_files
+        .iter()
+        .map(|path| context_file::read(path.clone()))
+        .collect::<Result<Vec<_>>>()?
+        .join("\n");
+    let variables = Variables::new(
+        &template,
+        defaults.as_ref().map_or(&args.model, |m| &m.name),
+        context,
+        args.variables.iter().cloned().collect::<BTreeMap<_, _>>(),
+    );
+    let system = template.system(&variables, &[])?;
+
+    let path = download(&args.model).await?;
+    let architecture = args
+        .architecture
+        .clone()
+        .or_else(|| defaults.as_ref().and_then(|m| m.architecture.clone()))
+        .unwrap_or_default();
+    let params = llm::ModelParameters {
+        use_gpu: args.gpu,
+        context_size: args.context_size,
+        ..Default::default()
+    };
+    let model: Arc<dyn llm::Model> = Arc::from(models::load(
+        &path,
+        &architecture,
+        "embedded",
+        params,
+        |progress| {
+            if let LoadProgress::TensorLoaded {
+                current_tensor,
+                tensor_count,
+            } = progress
+            {
+                eprint!("\rLoading tensor {}/{}", current_tensor, tensor_count);
+            }
+        },
+    )?);
+    eprintln!();
+
+    let context_files = args
+        .context_files
+        .iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    let sampling = defaults.and_then(|m| m.sampling).unwrap_or_default();
+    sampling.validate()?;
+    let mut manager = ModelManager {
+        model_id: args.model.clone(),
+        session: model.start_session(Default::default()),
+        model,
+        conversation: Conversation::new(&args.model, &template, &context_files),
+        template,
+        system,
+        variables,
+        sampling,
+        maximum_token_count: args.max_tokens,
+        seed: args.seed,
+        overflow: Default::default(),
+    };
+    // Checking out the start of the conversation warms up a fresh session.
+    manager.checkout(None)?;
+    Ok(manager)
+}
+
+/// Streams the response to standard output and records the exchange, even if
+/// it was cut short.
+fn respond(manager: &mut ModelManager, message: &str, interrupts: &Interrupts) -> Result<()> {
+    if let Some(overflow) = manager.fit(message, None)? {
+        eprintln!(
+            "[{} older messages no longer fit in the context window]",
+            overflow.dropped
+        );
+    }
+    let seed = manager.seed.unwrap_or_else(|| rand::random::<u64>() >> 11);
+    let mut response = String::new();
+    let (stats, cancelled) = interrupts.generate(|canceller| {
+        let stats = manager.infer(message, None, None, seed, |res| {
+            if let InferenceResponse::InferredToken(token) = res {
+                print!("{}", token);
+                let _ = io::stdout().flush();
+                response.push_str(&token);
+            }
+            canceller.inference_feedback()
+        });
+        (stats, canceller.is_cancelled())
+    });
+    let stats = stats.map_err(|err| anyhow!(err))?;
+    println!();
+    if cancelled {
+        eprintln!("[cancelled]");
+    }
+    manager.conversation.push(
+        Message {
+            role: Role::User,
+            content: message.to_string(),
+            stats: None,
+            seed: None,
+        },
+        Message {
+            role: Role::Assistant,
+            content: response.trim().to_string(),
+            stats: Some(Stats::from(&stats)),
+            seed: Some(seed),
+        },
+    );
+    Ok(())
+}
+
+fn chat(mut manager: ModelManager, interrupts: &Interrupts) -> Result<()> {
+    eprintln!(
+        "Chatting with {}. Type /undo to take back the last exchange or /exit to quit.",
+        manager.model_id
+    );
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        let result = match line.trim() {
+            "" => continue,
+            "/exit" | "/quit" => break,
+            "/undo" => manager.rewind().map(|_| ()),
+            message => respond(&mut manager, message, interrupts),
+        };
+        // A failed prompt shouldn't end the whole chat
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            continue;
+        }
+        conversations::save(&manager.conversation)?;
+    }
+    if !manager.conversation.exchanges.is_empty() {
+        eprintln!("Saved conversation {}", manager.conversation.id);
+    }
+    Ok(())
+}
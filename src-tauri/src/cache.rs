@@ -0,0 +1,166 @@
+use crate::config::get_cache_dir;
+use crate::models::file_sha256;
+use crate::snapshots::Entry;
+use crate::{settings, snapshots};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// How much disk space cached sessions may take up unless the settings say
+/// otherwise.
+const DEFAULT_LIMIT: u64 = 8 * 1024 * 1024 * 1024;
+
+/// The file model digests are remembered in, inside the cache directory.
+const DIGESTS_FILE: &str = "digests.json";
+
+/// The digest of a model file, along with what the file looked like when
+/// it was hashed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct ModelDigest {
+    size: u64,
+    modified: u64,
+    sha256: String,
+}
+
+/// Returns the cache key for a warmed up session. The warm-up prompt is
+/// hashed as rendered, so anything that changes what was fed into the session
+/// (the template, context files or template variables) changes the key.
+pub fn key(model_path: &Path, context_size: usize, warmup_prompt: &str) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(digest(&get_cache_dir()?, model_path)?);
+    hasher.update(context_size.to_le_bytes());
+    hasher.update(warmup_prompt.as_bytes());
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns the SHA-256 digest of the model file. Hashing a multi-gigabyte
+/// model takes a while, so it is only done once per file and remembered
+/// until the file's size or modification time change.
+fn digest(dir: &Path, model_path: &Path) -> Result<String> {
+    let metadata = fs::metadata(model_path)?;
+    let current = |sha256: String| -> Result<ModelDigest> {
+        Ok(ModelDigest {
+            size: metadata.len(),
+            modified: seconds(metadata.modified()?),
+            sha256,
+        })
+    };
+    let name = model_path.to_string_lossy().to_string();
+    let path = dir.join(DIGESTS_FILE);
+    let mut digests: HashMap<String, ModelDigest> = fs::read_to_string(&path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
+    if let Some(digest) = digests.get(&name) {
+        if *digest == current(digest.sha256.clone())? {
+            return Ok(digest.sha256.clone());
+        }
+    }
+    info!(model = name, "hashing model for the warm-up cache");
+    let digest = current(file_sha256(model_path)?)?;
+    let sha256 = digest.sha256.clone();
+    digests.insert(name, digest);
+    fs::write(path, serde_json::to_string_pretty(&digests)?)?;
+    Ok(sha256)
+}
+
+/// Returns the cached session for the provided key, if there is one.
+#[tracing::instrument(skip(model))]
+pub fn restore(
+    key: &str,
+    filename: &str,
+    model: &dyn llm::Model,
+) -> Result<Option<llm::InferenceSession>> {
+    let path = path(key)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let session = snapshots::read(&path, filename, model)?;
+    info!("restored warmed up session from cache");
+    Ok(Some(session))
+}
+
+#[tracing::instrument(skip(session))]
+pub fn save(key: &str, filename: &str, session: &mut llm::InferenceSession) -> Result<()> {
+    snapshots::write(&path(key)?, filename, session)?;
+    info!("cached warmed up session");
+    let limit = settings::read()
+        .unwrap_or_default()
+        .cache_limit
+        .unwrap_or(DEFAULT_LIMIT);
+    snapshots::evict(&get_cache_dir()?, limit, key)?;
+    Ok(())
+}
+
+/// Returns every cached session, most recently used first.
+pub fn list() -> Result<Vec<Entry>> {
+    snapshots::entries(&get_cache_dir()?)
+}
+
+/// Removes every cached session and returns the number of bytes freed.
+#[tracing::instrument]
+pub fn clear() -> Result<u64> {
+    let mut freed = 0;
+    for file in fs::read_dir(get_cache_dir()?)? {
+        let file = file?;
+        // Model digests aren't sessions and are expensive to work out again.
+        if file.metadata()?.is_file() && file.file_name() != DIGESTS_FILE {
+            freed += file.metadata()?.len();
+            fs::remove_file(file.path())?;
+        }
+    }
+    info!(freed, "cleared warm-up cache");
+    Ok(freed)
+}
+
+fn path(key: &str) -> Result<PathBuf> {
+    Ok(get_cache_dir()?.join(format!("{}.bin", key)))
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{digest, DIGESTS_FILE};
+    use crate::test_util::temp_dir;
+    use std::fs;
+
+    #[test]
+    fn test_digest() {
+        let dir = temp_dir("cache-digest");
+        let model = dir.join("model.bin");
+        fs::write(&model, "model").unwrap();
+        let first = digest(&dir, &model).unwrap();
+        assert_eq!(
+            first,
+            "9372c470eeadd5ecd9c3c74c2b3cb633f8e2f2fad799250a0f70d652b6b825e4"
+        );
+        assert!(dir.join(DIGESTS_FILE).exists());
+
+        // The remembered digest is used while the file is unchanged
+        let digests = fs::read_to_string(dir.join(DIGESTS_FILE)).unwrap();
+        fs::write(
+            dir.join(DIGESTS_FILE),
+            digests.replace(&first, "remembered"),
+        )
+        .unwrap();
+        assert_eq!(digest(&dir, &model).unwrap(), "remembered");
+
+        // Changing the file hashes it again
+        fs::write(&model, "another model").unwrap();
+        let second = digest(&dir, &model).unwrap();
+        assert_ne!(second, "remembered");
+        assert_ne!(second, first);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
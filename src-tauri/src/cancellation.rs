@@ -1,23 +1,26 @@
-use flume::{bounded, Receiver, SendError, Sender};
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use tracing::info;
 
+/// A flag that long-running work polls to find out whether it should stop.
+/// Cancelling never waits for the work to notice, since whatever is being
+/// cancelled may still be queued or may have already finished.
+#[derive(Default)]
 pub struct Canceller {
     cancelled: AtomicBool,
-    rx: Mutex<Receiver<()>>,
-    tx: Sender<()>,
 }
 
 impl Canceller {
     #[tracing::instrument(skip(self))]
-    pub async fn cancel(&self) -> Result<(), SendError<()>> {
-        info!("cancelling inference");
+    pub fn cancel(&self) {
+        info!("cancelling");
         self.cancelled.store(true, Ordering::Release);
-        self.tx.send_async(()).await
     }
 
+    /// Returns true if a cancellation has been requested.
     pub fn is_cancelled(&self) -> bool {
         self.cancelled.load(Ordering::Acquire)
     }
@@ -28,14 +31,7 @@ impl Canceller {
 
     #[tracing::instrument(skip(self))]
     pub fn inference_feedback(&self) -> Result<llm::InferenceFeedback, Infallible> {
-        // When a cancellation occurs, the sender will block until it is received at least
-        // once. We want to check and see if that message has been sent, and if so we'll cancel.
-        let cancelled = if let Ok(rx) = self.rx.try_lock() {
-            rx.try_recv().is_ok()
-        } else {
-            false
-        };
-        if cancelled || self.is_cancelled() {
+        if self.is_cancelled() {
             info!("sending halt");
             Ok(llm::InferenceFeedback::Halt)
         } else {
@@ -44,13 +40,96 @@ impl Canceller {
     }
 }
 
-impl Default for Canceller {
-    fn default() -> Self {
-        let (tx, rx) = bounded(0);
-        Self {
-            cancelled: Default::default(),
-            rx: Mutex::new(rx),
-            tx,
+/// Hands out a [`Canceller`] to every job, keyed by what the job works on,
+/// e.g. a session. Cancelling a key stops the jobs that are running or queued
+/// for it at the time, but neither the ones queued after nor those of any
+/// other key.
+#[derive(Default, Clone)]
+pub struct Cancellations {
+    jobs: Arc<Mutex<HashMap<String, Vec<Arc<Canceller>>>>>,
+}
+
+impl Cancellations {
+    /// Registers a new job for the key. The job is forgotten once it's
+    /// dropped.
+    pub fn register(&self, key: &str) -> Job {
+        let canceller = Arc::new(Canceller::default());
+        self.jobs
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .push(canceller.clone());
+        Job {
+            key: key.to_string(),
+            canceller,
+            jobs: self.jobs.clone(),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn cancel(&self, key: &str) {
+        if let Some(jobs) = self.jobs.lock().unwrap().get(key) {
+            for job in jobs {
+                job.cancel();
+            }
+        }
+    }
+}
+
+/// The [`Canceller`] of a single job registered with [`Cancellations`].
+pub struct Job {
+    key: String,
+    canceller: Arc<Canceller>,
+    jobs: Arc<Mutex<HashMap<String, Vec<Arc<Canceller>>>>>,
+}
+
+impl Deref for Job {
+    type Target = Canceller;
+
+    fn deref(&self) -> &Canceller {
+        &self.canceller
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(cancellers) = jobs.get_mut(&self.key) {
+            cancellers.retain(|c| !Arc::ptr_eq(c, &self.canceller));
+            if cancellers.is_empty() {
+                jobs.remove(&self.key);
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cancellation::Cancellations;
+
+    #[test]
+    fn test_cancellations() {
+        let cancellations = Cancellations::default();
+        let running = cancellations.register("default");
+        let queued = cancellations.register("default");
+        let other = cancellations.register("writing");
+
+        cancellations.cancel("default");
+        assert!(running.is_cancelled());
+        // A job that was still queued stays cancelled once it gets to run
+        assert!(queued.is_cancelled());
+        assert!(!other.is_cancelled());
+
+        // Jobs queued after the cancellation aren't affected
+        let next = cancellations.register("default");
+        assert!(!next.is_cancelled());
+
+        drop(running);
+        drop(queued);
+        drop(next);
+        assert!(!cancellations.jobs.lock().unwrap().contains_key("default"));
+        cancellations.cancel("writing");
+        assert!(other.is_cancelled());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::cancellation::Canceller;
+use crate::overflow;
+use crate::prompt::{StopSequenceFilter, Template, Variables};
+use crate::sampling::SamplingSettings;
+use anyhow::{anyhow, Result};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The most models that respond at once when comparing in parallel. Each of
+/// them already runs inference on several threads.
+pub const MAX_PARALLEL: usize = 4;
+
+/// A loaded model taking part in a comparison, along with the prompt
+/// template and sampling settings it's prompted with.
+pub struct Contender {
+    pub id: String,
+    pub model: Arc<dyn llm::Model>,
+    pub template: Template,
+    pub variables: Variables,
+    pub sampling: SamplingSettings,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Comparison {
+    /// The id of the model that responded
+    pub model: String,
+    pub message: String,
+    pub stats: llm::InferenceStats,
+    pub seed: u64,
+}
+
+/// Sends the message to every contender in a fresh session, up to
+/// `parallelism` at once, and calls `on_token` with the model's id and each
+/// piece of its response as it's generated.
+pub fn run<F>(
+    contenders: &[Contender],
+    message: &str,
+    maximum_token_count: Option<usize>,
+    seed: u64,
+    parallelism: usize,
+    canceller: &Canceller,
+    on_token: F,
+) -> Result<Vec<Comparison>>
+where
+    F: Fn(&str, String) + Sync,
+{
+    schedule(contenders, parallelism, |contender| {
+        respond(
+            contender,
+            message,
+            maximum_token_count,
+            seed,
+            canceller,
+            &on_token,
+        )
+    })
+}
+
+/// Calls `f` with every item on up to `parallelism` threads, returning the
+/// results in the order of the items.
+fn schedule<T, R, F>(items: &[T], parallelism: usize, f: F) -> Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R> + Sync,
+{
+    if parallelism <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(items.len()));
+    thread::scope(|scope| {
+        let handles = (0..parallelism.min(items.len()))
+            .map(|_| {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(index) else {
+                        return;
+                    };
+                    let result = f(item);
+                    results.lock().unwrap().push((index, result));
+                })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().try_for_each(|handle| {
+            handle
+                .join()
+                .map_err(|_| anyhow!("Comparison thread panicked"))
+        })
+    })?;
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+fn respond<F>(
+    contender: &Contender,
+    message: &str,
+    maximum_token_count: Option<usize>,
+    seed: u64,
+    canceller: &Canceller,
+    on_token: &F,
+) -> Result<Comparison>
+where
+    F: Fn(&str, String),
+{
+    let template = &contender.template;
+    let system = template.system(&contender.variables, &[])?;
+    let prompt = template.process(&system, &contender.variables, &[], message)?;
+    let tokens = overflow::tokenize(contender.model.as_ref(), &prompt, true)?;
+    let mut filter = StopSequenceFilter::new(&template.stop);
+    let mut response = String::new();
+    let mut emit = |text: String| {
+        if !text.is_empty() {
+            response.push_str(&text);
+            on_token(&contender.id, text);
+        }
+    };
+    let stats = contender
+        .model
+        .start_session(Default::default())
+        .infer(
+            contender.model.as_ref(),
+            &mut StdRng::seed_from_u64(seed),
+            &llm::InferenceRequest {
+                prompt: (&tokens).into(),
+                parameters: &contender.sampling.parameters(),
+                play_back_previous_tokens: false,
+                maximum_token_count,
+            },
+            &mut Default::default(),
+            |res| {
+                if let llm::InferenceResponse::InferredToken(token) = res {
+                    emit(filter.push(&token));
+                }
+                if filter.is_stopped() {
+                    Ok::<_, Infallible>(llm::InferenceFeedback::Halt)
+                } else {
+                    canceller.inference_feedback()
+                }
+            },
+        )
+        .map_err(|err| anyhow!("Error inferring with {}: {}", contender.id, err))?;
+    // Anything still held back turned out not to be a stop sequence.
+    emit(filter.finish());
+    Ok(Comparison {
+        model: contender.id.clone(),
+        message: response.trim().to_string(),
+        stats,
+        seed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compare::{schedule, MAX_PARALLEL};
+    use anyhow::anyhow;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_schedule() {
+        let items = (0..10).collect::<Vec<usize>>();
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let square = |item: &usize| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok(item * item)
+        };
+
+        let sequential = schedule(&items, 1, square).unwrap();
+        assert_eq!(most.swap(0, Ordering::SeqCst), 1);
+        // Running in parallel gives the same results in the same order, but
+        // never runs more than the limit at once
+        let parallel = schedule(&items, MAX_PARALLEL, square).unwrap();
+        assert_eq!(sequential, parallel);
+        assert_eq!(sequential, items.iter().map(|i| i * i).collect::<Vec<_>>());
+        assert!(most.load(Ordering::SeqCst) > 1);
+        assert!(most.load(Ordering::SeqCst) <= MAX_PARALLEL);
+
+        let failing = |item: &usize| match item {
+            3 => Err(anyhow!("failed")),
+            _ => Ok(*item),
+        };
+        assert!(schedule(&items, 1, failing).is_err());
+        assert!(schedule(&items, MAX_PARALLEL, failing).is_err());
+    }
+}
@@ -20,3 +20,35 @@ pub fn get_logs_dir() -> Result<PathBuf> {
     create_dir_all(&dir)?;
     Ok(dir)
 }
+
+pub fn get_conversations_dir() -> Result<PathBuf> {
+    let dir = get_app_dir()?.join("conversations");
+    create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+pub fn get_snapshots_dir() -> Result<PathBuf> {
+    let dir = get_app_dir()?.join("snapshots");
+    create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+pub fn get_cache_dir() -> Result<PathBuf> {
+    let dir = get_app_dir()?.join("cache");
+    create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// The file user-defined prompt templates are stored in.
+pub fn get_templates_file() -> Result<PathBuf> {
+    let dir = get_app_dir()?;
+    create_dir_all(&dir)?;
+    Ok(dir.join("templates.json"))
+}
+
+/// The file application-wide settings are stored in.
+pub fn get_settings_file() -> Result<PathBuf> {
+    let dir = get_app_dir()?;
+    create_dir_all(&dir)?;
+    Ok(dir.join("settings.json"))
+}
@@ -0,0 +1,463 @@
+use crate::config::get_conversations_dir;
+use crate::prompt::Template;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+const DEFAULT_TITLE: &str = "New conversation";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+/// A copy of [`llm::InferenceStats`] that we can read back from disk.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    pub feed_prompt_duration: Duration,
+    pub prompt_tokens: usize,
+    pub predict_duration: Duration,
+    pub predict_tokens: usize,
+}
+
+impl From<&llm::InferenceStats> for Stats {
+    fn from(stats: &llm::InferenceStats) -> Self {
+        Self {
+            feed_prompt_duration: stats.feed_prompt_duration,
+            prompt_tokens: stats.prompt_tokens,
+            predict_duration: stats.predict_duration,
+            predict_tokens: stats.predict_tokens,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+    #[serde(default)]
+    pub stats: Option<Stats>,
+    /// The seed an assistant message was generated with
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Conversation {
+    pub id: String,
+    pub title: String,
+    /// Seconds since the unix epoch
+    pub created_at: u64,
+    pub updated_at: u64,
+    /// The filename of the model the conversation was held with
+    pub model: String,
+    pub template: Template,
+    #[serde(default)]
+    pub context_files: Vec<String>,
+    /// The messages on the current branch
+    #[serde(default)]
+    pub messages: Vec<Message>,
+    /// Every exchange on every branch, indexed by id
+    #[serde(default)]
+    pub exchanges: Vec<Exchange>,
+    /// The last exchange on the current branch
+    #[serde(default)]
+    pub head: Option<usize>,
+    /// The number of messages at the start of the current branch that no
+    /// longer fit in the context window
+    #[serde(default)]
+    pub dropped: usize,
+    /// A summary of the dropped messages, written by the model
+    #[serde(default)]
+    pub summary: Option<String>,
+}
+
+/// A prompt and its response. Exchanges form a tree: each one follows on from
+/// its parent, and exchanges with the same parent are alternatives to each
+/// other.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Exchange {
+    pub id: usize,
+    pub parent: Option<usize>,
+    pub prompt: Message,
+    pub response: Message,
+    /// What had been dropped from the context window when the exchange
+    /// took place, so that checking it out can pick up from there
+    #[serde(default)]
+    pub dropped: usize,
+    #[serde(default)]
+    pub summary: Option<String>,
+}
+
+impl Conversation {
+    pub fn new(model: &str, template: &Template, context_files: &[String]) -> Self {
+        let now = now();
+        Self {
+            // Millisecond timestamps keep ids sortable, the random suffix keeps
+            // them unique.
+            id: format!(
+                "{:x}-{:08x}",
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis(),
+                rand::random::<u32>()
+            ),
+            title: DEFAULT_TITLE.to_string(),
+            created_at: now,
+            updated_at: now,
+            model: model.to_string(),
+            template: template.clone(),
+            context_files: context_files.to_vec(),
+            messages: vec![],
+            exchanges: vec![],
+            head: None,
+            dropped: 0,
+            summary: None,
+        }
+    }
+
+    /// Adds an exchange after the current head and returns its id.
+    pub fn push(&mut self, prompt: Message, response: Message) -> usize {
+        // Untitled conversations are named after the first thing the user said.
+        if self.title == DEFAULT_TITLE {
+            let title = prompt.content.trim();
+            self.title = match title.char_indices().nth(40) {
+                Some((i, _)) => format!("{}…", &title[..i]),
+                None => title.to_string(),
+            };
+        }
+        let id = self.exchanges.len();
+        self.messages.push(prompt.clone());
+        self.messages.push(response.clone());
+        self.exchanges.push(Exchange {
+            id,
+            parent: self.head,
+            prompt,
+            response,
+            dropped: self.dropped,
+            summary: self.summary.clone(),
+        });
+        self.head = Some(id);
+        self.updated_at = now();
+        id
+    }
+
+    /// Makes the provided exchange the last one on the current branch, or
+    /// goes back to before the first exchange if there is none. What was
+    /// dropped from the context window goes back to how it was then.
+    pub fn checkout(&mut self, head: Option<usize>) -> Result<()> {
+        if head.is_some_and(|id| id >= self.exchanges.len()) {
+            return Err(anyhow!("Exchange not found"));
+        }
+        (self.dropped, self.summary) = match head {
+            Some(id) => (
+                self.exchanges[id].dropped,
+                self.exchanges[id].summary.clone(),
+            ),
+            None => (0, None),
+        };
+        let mut branch = vec![];
+        let mut next = head;
+        while let Some(id) = next {
+            let exchange = &self.exchanges[id];
+            branch.push(exchange.response.clone());
+            branch.push(exchange.prompt.clone());
+            next = exchange.parent;
+        }
+        branch.reverse();
+        self.messages = branch;
+        self.head = head;
+        self.updated_at = now();
+        Ok(())
+    }
+
+    /// Returns the provided exchange and its alternatives, oldest first.
+    pub fn siblings(&self, id: usize) -> Result<Vec<Exchange>> {
+        let parent = self
+            .exchanges
+            .get(id)
+            .ok_or(anyhow!("Exchange not found"))?
+            .parent;
+        Ok(self
+            .exchanges
+            .iter()
+            .filter(|e| e.parent == parent)
+            .cloned()
+            .collect())
+    }
+
+    /// Returns the last exchange of the most recent branch that goes through
+    /// the provided exchange.
+    pub fn tip(&self, id: usize) -> usize {
+        let mut id = id;
+        while let Some(child) = self.exchanges.iter().rev().find(|e| e.parent == Some(id)) {
+            id = child.id;
+        }
+        id
+    }
+
+    /// The id of the session snapshot taken after the provided exchange.
+    pub fn snapshot_id(&self, exchange: usize) -> String {
+        format!("{}-{}", self.id, exchange)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    pub id: String,
+    pub title: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub model: String,
+    pub message_count: usize,
+}
+
+impl From<&Conversation> for Summary {
+    fn from(conversation: &Conversation) -> Self {
+        Self {
+            id: conversation.id.clone(),
+            title: conversation.title.clone(),
+            created_at: conversation.created_at,
+            updated_at: conversation.updated_at,
+            model: conversation.model.clone(),
+            message_count: conversation.messages.len(),
+        }
+    }
+}
+
+/// Returns all stored conversations, most recently updated first.
+pub fn list() -> Result<Vec<Summary>> {
+    list_in(&get_conversations_dir()?)
+}
+
+fn list_in(dir: &Path) -> Result<Vec<Summary>> {
+    let mut summaries = vec![];
+    for file in fs::read_dir(dir)? {
+        let path = file?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        // One unreadable conversation shouldn't hide all the others.
+        match fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| Ok(serde_json::from_str::<Conversation>(&s)?))
+        {
+            Ok(conversation) => summaries.push(Summary::from(&conversation)),
+            Err(err) => warn!(
+                error = err.to_string(),
+                path = path.to_str().unwrap_or_default(),
+                "reading conversation"
+            ),
+        }
+    }
+    summaries.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
+    Ok(summaries)
+}
+
+pub fn load(id: &str) -> Result<Conversation> {
+    load_from(&get_conversations_dir()?, id)
+}
+
+fn load_from(dir: &Path, id: &str) -> Result<Conversation> {
+    let path = path(dir, id)?;
+    if !path.exists() {
+        return Err(anyhow!("Conversation not found"));
+    }
+    let mut conversation: Conversation = serde_json::from_str(&fs::read_to_string(path)?)?;
+    // Conversations saved before branching only have a list of messages.
+    if conversation.exchanges.is_empty() && !conversation.messages.is_empty() {
+        let (title, updated_at) = (conversation.title.clone(), conversation.updated_at);
+        for pair in std::mem::take(&mut conversation.messages).chunks(2) {
+            if let [prompt, response] = pair {
+                conversation.push(prompt.clone(), response.clone());
+            }
+        }
+        conversation.title = title;
+        conversation.updated_at = updated_at;
+    }
+    Ok(conversation)
+}
+
+pub fn save(conversation: &Conversation) -> Result<()> {
+    save_in(&get_conversations_dir()?, conversation)
+}
+
+fn save_in(dir: &Path, conversation: &Conversation) -> Result<()> {
+    // Write to a temporary file first so that a crash mid-write can't
+    // corrupt an existing conversation.
+    let path = path(dir, &conversation.id)?;
+    let temporary = path.with_extension("json.tmp");
+    fs::write(&temporary, serde_json::to_string_pretty(conversation)?)?;
+    fs::rename(temporary, path)?;
+    Ok(())
+}
+
+#[tracing::instrument]
+pub fn rename(id: &str, title: &str) -> Result<Conversation> {
+    let conversation = rename_in(&get_conversations_dir()?, id, title)?;
+    info!("renamed conversation");
+    Ok(conversation)
+}
+
+fn rename_in(dir: &Path, id: &str, title: &str) -> Result<Conversation> {
+    let mut conversation = load_from(dir, id)?;
+    conversation.title = title.to_string();
+    save_in(dir, &conversation)?;
+    Ok(conversation)
+}
+
+#[tracing::instrument]
+pub fn delete(id: &str) -> Result<()> {
+    fs::remove_file(path(&get_conversations_dir()?, id)?)?;
+    info!("deleted conversation");
+    Ok(())
+}
+
+fn path(dir: &Path, id: &str) -> Result<PathBuf> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(anyhow!("Invalid conversation id"));
+    }
+    Ok(dir.join(format!("{}.json", id)))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conversations::{list_in, load_from, path, rename_in, save_in, Conversation, Role};
+    use crate::prompt::AVAILABLE_TEMPLATES;
+    use crate::test_util::{message, temp_dir};
+    use std::env;
+    use std::fs;
+
+    fn push(conversation: &mut Conversation, prompt: &str, response: &str) -> usize {
+        conversation.push(
+            message(Role::User, prompt),
+            message(Role::Assistant, response),
+        )
+    }
+
+    #[test]
+    fn test_branches() {
+        let mut conversation = Conversation::new("model.bin", &AVAILABLE_TEMPLATES[0], &[]);
+        let first = push(&mut conversation, "Hi", "Hello!");
+        let second = push(&mut conversation, "Tell me a joke", "No.");
+        assert_eq!(conversation.title, "Hi");
+
+        // Regenerating the second response adds an alternative to it
+        conversation.checkout(Some(first)).unwrap();
+        let alternative = push(&mut conversation, "Tell me a joke", "Knock knock");
+        let siblings = conversation.siblings(second).unwrap();
+        assert_eq!(
+            siblings.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![second, alternative]
+        );
+        assert_eq!(conversation.messages.len(), 4);
+        assert_eq!(conversation.messages[3].content, "Knock knock");
+
+        let follow_up = push(&mut conversation, "Who's there?", "Lettuce");
+        conversation
+            .checkout(Some(conversation.tip(second)))
+            .unwrap();
+        assert_eq!(conversation.messages[3].content, "No.");
+        assert_eq!(conversation.tip(alternative), follow_up);
+        assert_eq!(conversation.tip(first), alternative + 1);
+
+        // Checking out an exchange brings back what had been dropped then
+        conversation.dropped = 2;
+        conversation.summary = Some("They said hi.".to_string());
+        let later = push(&mut conversation, "Another one?", "No.");
+        conversation.checkout(Some(first)).unwrap();
+        assert_eq!(conversation.dropped, 0);
+        assert_eq!(conversation.summary, None);
+        conversation.checkout(Some(later)).unwrap();
+        assert_eq!(conversation.dropped, 2);
+        assert_eq!(conversation.summary.as_deref(), Some("They said hi."));
+
+        conversation.checkout(None).unwrap();
+        assert!(conversation.messages.is_empty());
+        assert!(conversation.checkout(Some(10)).is_err());
+    }
+
+    #[test]
+    fn test_storage() {
+        let dir = temp_dir("conversations");
+        let mut older = Conversation::new("model.bin", &AVAILABLE_TEMPLATES[0], &[]);
+        push(&mut older, "Hi", "Hello!");
+        older.updated_at -= 10;
+        let newer = Conversation::new("model.bin", &AVAILABLE_TEMPLATES[0], &[]);
+        save_in(&dir, &older).unwrap();
+        save_in(&dir, &newer).unwrap();
+
+        let summaries = list_in(&dir).unwrap();
+        assert_eq!(
+            summaries.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(),
+            vec![newer.id.as_str(), older.id.as_str()]
+        );
+        assert_eq!(summaries[1].message_count, 2);
+
+        let renamed = rename_in(&dir, &older.id, "Greetings").unwrap();
+        assert_eq!(renamed.title, "Greetings");
+        let loaded = load_from(&dir, &older.id).unwrap();
+        assert_eq!(loaded.title, "Greetings");
+        assert_eq!(loaded.messages.len(), 2);
+        assert_eq!(loaded.head, older.head);
+
+        fs::remove_file(path(&dir, &older.id).unwrap()).unwrap();
+        assert!(load_from(&dir, &older.id).is_err());
+        assert!(rename_in(&dir, &older.id, "Gone").is_err());
+        assert_eq!(list_in(&dir).unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_atomic_save() {
+        let dir = temp_dir("atomic-save");
+        let mut conversation = Conversation::new("model.bin", &AVAILABLE_TEMPLATES[0], &[]);
+        push(&mut conversation, "Hi", "Hello!");
+        save_in(&dir, &conversation).unwrap();
+
+        // Nothing is left behind once the write has completed
+        let files = fs::read_dir(&dir).unwrap().count();
+        assert_eq!(files, 1);
+
+        // A write that was interrupted before the rename leaves the saved
+        // conversation untouched and isn't listed
+        let path = path(&dir, &conversation.id).unwrap();
+        fs::write(path.with_extension("json.tmp"), "{\"id\":").unwrap();
+        let loaded = load_from(&dir, &conversation.id).unwrap();
+        assert_eq!(loaded.messages.len(), 2);
+        assert_eq!(list_in(&dir).unwrap().len(), 1);
+
+        // The next save replaces the leftover temporary file
+        save_in(&dir, &conversation).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_id() {
+        let dir = env::temp_dir();
+        assert!(path(&dir, "").is_err());
+        assert!(path(&dir, "../settings").is_err());
+        assert!(load_from(&dir, "a/b").is_err());
+    }
+}
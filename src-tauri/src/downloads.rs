@@ -0,0 +1,447 @@
+use crate::events::Event;
+use anyhow::{anyhow, Result};
+use chitchat_lib::cancellation::Canceller;
+use chitchat_lib::config::get_models_dir;
+use chitchat_lib::models::{get_local_model, DownloadCancelled, AVAILABLE_MODELS};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tauri::async_runtime::JoinHandle;
+use tauri::Window;
+use tracing::{error, info};
+
+/// The number of downloads that are allowed to run at the same time. Any
+/// other downloads remain queued until a slot frees up.
+const MAX_CONCURRENT_DOWNLOADS: usize = 3;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum DownloadStatus {
+    Queued,
+    Downloading,
+    Paused,
+    Completed,
+    Failed,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Download {
+    pub filename: String,
+    pub status: DownloadStatus,
+    pub downloaded: u64,
+    pub total: u64,
+    pub error: Option<String>,
+    #[serde(skip)]
+    canceller: Arc<Canceller>,
+}
+
+impl Download {
+    fn new(filename: &str) -> Self {
+        Self {
+            filename: filename.to_string(),
+            status: DownloadStatus::Queued,
+            downloaded: 0,
+            total: 0,
+            error: None,
+            canceller: Default::default(),
+        }
+    }
+}
+
+/// The state of every download, kept apart from the tasks doing the actual
+/// downloading so that it only ever changes in the ways allowed here.
+#[derive(Default)]
+struct Queue {
+    downloads: Vec<Download>,
+    /// Models that are being downloaded in the foreground by a model load
+    foreground: HashSet<String>,
+}
+
+impl Queue {
+    fn find(&mut self, filename: &str) -> Result<&mut Download> {
+        self.downloads
+            .iter_mut()
+            .find(|d| d.filename == filename)
+            .ok_or(anyhow!("Download not found"))
+    }
+
+    fn is_active(&self, filename: &str) -> bool {
+        self.foreground.contains(filename)
+            || self.downloads.iter().any(|d| {
+                d.filename == filename
+                    && matches!(
+                        d.status,
+                        DownloadStatus::Queued | DownloadStatus::Downloading
+                    )
+            })
+    }
+
+    /// Claims the model for a foreground download, so that nothing else
+    /// writes to its partial file until the claim is released.
+    fn claim(&mut self, filename: &str) -> Result<()> {
+        if self.is_active(filename) {
+            return Err(anyhow!("Model is still being downloaded"));
+        }
+        self.foreground.insert(filename.to_string());
+        Ok(())
+    }
+
+    /// Adds the download to the back of the queue, replacing a finished one
+    /// for the same model.
+    fn enqueue(&mut self, filename: &str) -> Result<()> {
+        if self.foreground.contains(filename) {
+            return Err(anyhow!("Model is already being downloaded"));
+        }
+        if let Some(existing) = self.downloads.iter().find(|d| d.filename == filename) {
+            if !matches!(
+                existing.status,
+                DownloadStatus::Completed | DownloadStatus::Failed
+            ) {
+                return Err(anyhow!("Model is already in the download queue"));
+            }
+        }
+        self.downloads.retain(|d| d.filename != filename);
+        self.downloads.push(Download::new(filename));
+        Ok(())
+    }
+
+    /// Marks the download as paused and tells its task to stop.
+    fn pause(&mut self, filename: &str) -> Result<&Download> {
+        let download = self.find(filename)?;
+        if !matches!(
+            download.status,
+            DownloadStatus::Queued | DownloadStatus::Downloading
+        ) {
+            return Err(anyhow!("Download is not in progress"));
+        }
+        download.status = DownloadStatus::Paused;
+        download.canceller.cancel();
+        Ok(download)
+    }
+
+    fn check_resumable(&mut self, filename: &str) -> Result<()> {
+        match self.find(filename)?.status {
+            DownloadStatus::Paused | DownloadStatus::Failed => Ok(()),
+            _ => Err(anyhow!("Download is not paused")),
+        }
+    }
+
+    /// Puts a paused or failed download back in the queue. Its previous task
+    /// must have exited by now, since the new one appends to the same file.
+    fn resume(&mut self, filename: &str) -> Result<()> {
+        self.check_resumable(filename)?;
+        let download = self.find(filename)?;
+        download.status = DownloadStatus::Queued;
+        download.error = None;
+        download.canceller = Default::default();
+        Ok(())
+    }
+
+    /// Forgets about the download and tells its task to stop.
+    fn remove(&mut self, filename: &str) -> Result<()> {
+        self.find(filename)?.canceller.cancel();
+        self.downloads.retain(|d| d.filename != filename);
+        Ok(())
+    }
+
+    /// Marks as many queued downloads as there are free download slots as
+    /// downloading, and returns them so that they can be started.
+    fn start(&mut self) -> Vec<(String, Arc<Canceller>)> {
+        let mut active = self
+            .downloads
+            .iter()
+            .filter(|d| d.status == DownloadStatus::Downloading)
+            .count();
+        let mut started = vec![];
+        for download in self.downloads.iter_mut() {
+            if active >= MAX_CONCURRENT_DOWNLOADS {
+                break;
+            }
+            if download.status != DownloadStatus::Queued {
+                continue;
+            }
+            download.status = DownloadStatus::Downloading;
+            active += 1;
+            started.push((download.filename.clone(), download.canceller.clone()));
+        }
+        started
+    }
+}
+
+/// Downloads catalog models in the background, independently of the model
+/// that is currently loaded. Each download gets its own [`Canceller`] which
+/// is used to pause or remove it, and because downloads are written to a
+/// partial file, a paused download picks up where it left off.
+#[derive(Clone, Default)]
+pub struct DownloadManager {
+    queue: Arc<Mutex<Queue>>,
+    /// The task that last ran each download
+    tasks: Arc<Mutex<HashMap<String, JoinHandle<()>>>>,
+}
+
+impl DownloadManager {
+    pub fn list(&self) -> Vec<Download> {
+        self.queue.lock().unwrap().downloads.clone()
+    }
+
+    /// Claims the model for a download that runs in the foreground, refusing
+    /// if it's already being downloaded. The model can't be queued until the
+    /// returned guard is dropped.
+    pub fn claim(&self, filename: &str) -> Result<Foreground> {
+        self.queue.lock().unwrap().claim(filename)?;
+        Ok(Foreground {
+            queue: self.queue.clone(),
+            filename: filename.to_string(),
+        })
+    }
+
+    #[tracing::instrument(skip(self, window))]
+    pub fn enqueue(&self, window: &Window, filename: &str) -> Result<()> {
+        if !AVAILABLE_MODELS.iter().any(|m| m.filename == filename) {
+            return Err(anyhow!("Model not found"));
+        }
+        if get_models_dir()?.join(filename).exists() {
+            return Err(anyhow!("Model is already downloaded"));
+        }
+        self.queue.lock().unwrap().enqueue(filename)?;
+        info!("queued download");
+        self.schedule(window);
+        Ok(())
+    }
+
+    /// Stops the download without waiting for its task to exit, which
+    /// resuming or removing the download does instead.
+    #[tracing::instrument(skip(self, window))]
+    pub fn pause(&self, window: &Window, filename: &str) -> Result<()> {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            let download = queue.pause(filename)?;
+            send_progress(window, download, 0, None);
+        }
+        if let Some(task) = self.tasks.lock().unwrap().get(filename) {
+            task.abort();
+        }
+        self.schedule(window);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, window))]
+    pub async fn resume(&self, window: &Window, filename: &str) -> Result<()> {
+        self.queue.lock().unwrap().check_resumable(filename)?;
+        self.stopped(filename).await;
+        self.queue.lock().unwrap().resume(filename)?;
+        self.schedule(window);
+        Ok(())
+    }
+
+    /// Stops the download if it's running, forgets about it, and deletes
+    /// any partially downloaded data.
+    #[tracing::instrument(skip(self, window))]
+    pub async fn remove(&self, window: &Window, filename: &str) -> Result<()> {
+        self.queue.lock().unwrap().remove(filename)?;
+        // The task may still have the partial file open.
+        self.stopped(filename).await;
+        let partial = get_models_dir()?.join(format!("{}.part", filename));
+        if partial.exists() {
+            fs::remove_file(partial)?;
+        }
+        self.schedule(window);
+        Ok(())
+    }
+
+    /// Waits for the task that last ran the download to exit. The task only
+    /// notices its canceller once the next chunk arrives, so it's aborted too,
+    /// which takes effect the next time it waits on the network.
+    async fn stopped(&self, filename: &str) {
+        let task = self.tasks.lock().unwrap().remove(filename);
+        if let Some(task) = task {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+
+    /// Starts as many queued downloads as there are free download slots.
+    fn schedule(&self, window: &Window) {
+        let started = self.queue.lock().unwrap().start();
+        let mut tasks = self.tasks.lock().unwrap();
+        for (filename, canceller) in started {
+            let task = tauri::async_runtime::spawn(run(
+                self.clone(),
+                window.clone(),
+                filename.clone(),
+                canceller,
+            ));
+            tasks.insert(filename, task);
+        }
+    }
+
+    fn update<F>(&self, filename: &str, f: F)
+    where
+        F: FnOnce(&mut Download),
+    {
+        if let Ok(download) = self.queue.lock().unwrap().find(filename) {
+            f(download)
+        }
+    }
+}
+
+/// A foreground download's claim on a model, released when dropped.
+pub struct Foreground {
+    queue: Arc<Mutex<Queue>>,
+    filename: String,
+}
+
+impl Drop for Foreground {
+    fn drop(&mut self) {
+        self.queue.lock().unwrap().foreground.remove(&self.filename);
+    }
+}
+
+#[tracing::instrument(skip(manager, window, canceller))]
+async fn run(
+    manager: DownloadManager,
+    window: Window,
+    filename: String,
+    canceller: Arc<Canceller>,
+) {
+    let started = Instant::now();
+    // Resumed downloads report the bytes that were already on disk, so we
+    // only count what we download in this run towards the speed.
+    let offset = Mutex::new(None);
+    let result = get_local_model(&filename, &canceller, |downloaded, total, _| {
+        let initial = *offset.lock().unwrap().get_or_insert(downloaded);
+        let elapsed = started.elapsed().as_secs_f64();
+        let speed = if elapsed > 0.0 {
+            (downloaded.saturating_sub(initial) as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        let eta = (speed > 0).then(|| total.saturating_sub(downloaded) / speed);
+        manager.update(&filename, |download| {
+            download.downloaded = downloaded;
+            download.total = total;
+            send_progress(&window, download, speed, eta);
+        });
+    })
+    .await;
+
+    match result {
+        Ok(_) => {
+            info!("finished background download");
+            manager.update(&filename, |download| {
+                download.status = DownloadStatus::Completed;
+                download.downloaded = download.total;
+                send_progress(&window, download, 0, None);
+            });
+        }
+        // Whoever cancelled the download has already updated its status.
+        Err(err) if err.is::<DownloadCancelled>() => {}
+        Err(err) => {
+            error!(error = err.to_string(), "background download failed");
+            manager.update(&filename, |download| {
+                download.status = DownloadStatus::Failed;
+                download.error = Some(err.to_string());
+                send_progress(&window, download, 0, None);
+            });
+        }
+    }
+    manager.schedule(&window);
+}
+
+fn send_progress(window: &Window, download: &Download, speed: u64, eta: Option<u64>) {
+    Event::DownloadProgress {
+        filename: download.filename.clone(),
+        status: download.status,
+        downloaded: download.downloaded,
+        total: download.total,
+        speed,
+        eta,
+    }
+    .send(window);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::downloads::{DownloadStatus, Queue, MAX_CONCURRENT_DOWNLOADS};
+
+    fn status(queue: &mut Queue, filename: &str) -> DownloadStatus {
+        queue.find(filename).unwrap().status
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let mut queue = Queue::default();
+        queue.enqueue("a.bin").unwrap();
+        assert!(queue.enqueue("a.bin").is_err());
+        assert!(queue.resume("a.bin").is_err());
+
+        let started = queue.start();
+        assert_eq!(started.len(), 1);
+        assert_eq!(status(&mut queue, "a.bin"), DownloadStatus::Downloading);
+        assert!(queue.is_active("a.bin"));
+
+        // Pausing stops the running task, resuming hands a new one a fresh canceller
+        queue.pause("a.bin").unwrap();
+        assert!(started[0].1.is_cancelled());
+        assert!(!queue.is_active("a.bin"));
+        assert!(queue.pause("a.bin").is_err());
+        queue.resume("a.bin").unwrap();
+        assert_eq!(status(&mut queue, "a.bin"), DownloadStatus::Queued);
+        let restarted = queue.start();
+        assert!(!restarted[0].1.is_cancelled());
+        assert!(queue.resume("a.bin").is_err());
+    }
+
+    #[test]
+    fn test_concurrency_limit() {
+        let mut queue = Queue::default();
+        let filenames = (0..MAX_CONCURRENT_DOWNLOADS + 1)
+            .map(|i| format!("{}.bin", i))
+            .collect::<Vec<_>>();
+        for filename in &filenames {
+            queue.enqueue(filename).unwrap();
+        }
+        assert_eq!(queue.start().len(), MAX_CONCURRENT_DOWNLOADS);
+        assert!(queue.start().is_empty());
+        let last = filenames.last().unwrap();
+        assert_eq!(status(&mut queue, last), DownloadStatus::Queued);
+
+        // A paused download frees up its slot
+        queue.pause(&filenames[0]).unwrap();
+        let started = queue.start();
+        assert_eq!(started.len(), 1);
+        assert_eq!(&started[0].0, last);
+    }
+
+    #[test]
+    fn test_remove_and_enqueue_again() {
+        let mut queue = Queue::default();
+        queue.enqueue("a.bin").unwrap();
+        let started = queue.start();
+        queue.remove("a.bin").unwrap();
+        assert!(started[0].1.is_cancelled());
+        assert!(queue.find("a.bin").is_err());
+        assert!(queue.remove("a.bin").is_err());
+
+        queue.enqueue("a.bin").unwrap();
+        queue.find("a.bin").unwrap().status = DownloadStatus::Failed;
+        queue.enqueue("a.bin").unwrap();
+        assert_eq!(status(&mut queue, "a.bin"), DownloadStatus::Queued);
+    }
+
+    #[test]
+    fn test_foreground_claim() {
+        let mut queue = Queue::default();
+        queue.claim("a.bin").unwrap();
+        assert!(queue.is_active("a.bin"));
+        assert!(queue.claim("a.bin").is_err());
+        assert!(queue.enqueue("a.bin").is_err());
+
+        queue.foreground.remove("a.bin");
+        queue.enqueue("a.bin").unwrap();
+        assert!(queue.claim("a.bin").is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::downloads::DownloadStatus;
+use chitchat_lib::overflow::OverflowStrategy;
 use serde::Serialize;
 use tauri::Window;
 use tracing::error;
@@ -5,8 +7,43 @@ use tracing::error;
 #[derive(Serialize, Debug)]
 #[serde(tag = "untagged")]
 pub enum Event {
-    ModelLoading { message: String, progress: f32 },
-    PromptResponse { message: String },
+    ModelLoading {
+        message: String,
+        progress: f32,
+    },
+    PromptResponse {
+        message: String,
+    },
+    DownloadCancelled {
+        filename: String,
+    },
+    DownloadProgress {
+        filename: String,
+        status: DownloadStatus,
+        downloaded: u64,
+        total: u64,
+        /// Bytes per second
+        speed: u64,
+        /// Estimated seconds remaining
+        eta: Option<u64>,
+    },
+    /// Older messages were taken out of the context window to make room
+    ContextOverflow {
+        strategy: OverflowStrategy,
+        /// The number of messages that were dropped
+        dropped: usize,
+        summary: Option<String>,
+    },
+    /// The number of tokens a context file takes up in the context window
+    ContextFileTokens {
+        path: String,
+        tokens: usize,
+    },
+    /// A piece of one model's response in a comparison
+    ComparisonResponse {
+        model: String,
+        message: String,
+    },
 }
 
 impl Event {
@@ -14,6 +51,11 @@ impl Event {
         match self {
             Event::ModelLoading { .. } => "model_loading",
             Event::PromptResponse { .. } => "prompt_response",
+            Event::DownloadCancelled { .. } => "download_cancelled",
+            Event::DownloadProgress { .. } => "download_progress",
+            Event::ContextOverflow { .. } => "context_overflow",
+            Event::ContextFileTokens { .. } => "context_file_tokens",
+            Event::ComparisonResponse { .. } => "comparison_response",
         }
     }
 
@@ -0,0 +1,310 @@
+use crate::models::Quantization;
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const GGML_MAGIC: u32 = 0x6767_6d6c;
+const GGMF_MAGIC: u32 = 0x6767_6d66;
+const GGJT_MAGIC: u32 = 0x6767_6a74;
+
+/// Everything we were able to learn about a model by reading its header.
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Inspection {
+    /// The id of the detected architecture in `AVAILABLE_ARCHITECTURES`
+    pub architecture: Option<String>,
+    pub vocabulary_size: Option<usize>,
+    /// The context length the model was trained with
+    pub context_length: Option<u64>,
+    pub embedding_size: Option<u64>,
+    pub layers: Option<u64>,
+    pub quantization: Option<Quantization>,
+    /// The ggml file type, e.g. `Q4_K_M`
+    pub file_type: Option<String>,
+}
+
+impl Inspection {
+    /// Estimates the memory, in bytes, that the key/value cache of a single
+    /// session takes up with the provided context size. Sessions keep a key
+    /// and a value for every layer and position as 16-bit floats.
+    pub fn session_memory(&self, context_size: usize) -> Option<u64> {
+        Some(2 * self.layers? * self.embedding_size? * context_size as u64 * 2)
+    }
+}
+
+lazy_static! {
+    /// Inspections by model path, along with the modification time and size
+    /// of the file they were made from.
+    static ref INSPECTIONS: Mutex<HashMap<PathBuf, (SystemTime, u64, Inspection)>> =
+        Default::default();
+}
+
+/// Reads the header of the model file at the provided path and works out
+/// which architecture and hyperparameters it uses. Finding that out can mean
+/// reading through the whole vocabulary, so the result is kept around until
+/// the file changes.
+#[tracing::instrument]
+pub fn inspect(path: &Path) -> Result<Inspection> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?;
+    if let Some((time, size, inspection)) = INSPECTIONS.lock().unwrap().get(path) {
+        if *time == modified && *size == metadata.len() {
+            return Ok(inspection.clone());
+        }
+    }
+    let inspection = inspect_ggml(&mut BufReader::new(File::open(path)?))?;
+    INSPECTIONS.lock().unwrap().insert(
+        path.to_path_buf(),
+        (modified, metadata.len(), inspection.clone()),
+    );
+    Ok(inspection)
+}
+
+/// Describes where the interesting hyperparameters live in the header of a
+/// legacy GGML file for a given architecture. The file type is always last.
+struct Layout {
+    architecture: &'static str,
+    /// The number of 32-bit hyperparameter fields
+    fields: usize,
+    vocabulary: usize,
+    context: Option<usize>,
+    embedding: usize,
+    layers: usize,
+    /// Sanity checks on the hyperparameters that tell apart architectures
+    /// with the same number of fields.
+    check: fn(&[u32]) -> bool,
+}
+
+// Legacy GGML files don't say which architecture they are, so we try each
+// layout in turn. More specific layouts need to come first.
+const LAYOUTS: [Layout; 6] = [
+    // n_vocab, n_embd, n_mult, n_head, n_layer, n_rot, ftype
+    Layout {
+        architecture: "llama",
+        fields: 7,
+        vocabulary: 0,
+        context: None,
+        embedding: 1,
+        layers: 4,
+        check: |h| divides(h[1], h[3]) && h[5] == h[1] / h[3],
+    },
+    // n_vocab, n_ctx, n_embd, n_head, n_layer, n_rot, use_parallel_residual, ftype
+    Layout {
+        architecture: "gpt-neo-x",
+        fields: 8,
+        vocabulary: 0,
+        context: Some(1),
+        embedding: 2,
+        layers: 4,
+        check: |h| divides(h[2], h[3]) && h[5] <= h[2] / h[3] && h[6] <= 1,
+    },
+    // n_embd, max_seq_len, n_head, n_layer, n_vocab, alibi_bias_max, clip_qkv, ftype
+    Layout {
+        architecture: "mpt",
+        fields: 8,
+        vocabulary: 4,
+        context: Some(1),
+        embedding: 0,
+        layers: 3,
+        check: |h| divides(h[0], h[2]) && h[3] > 0,
+    },
+    // n_vocab, n_ctx, n_embd, n_head, n_layer, n_rot, ftype
+    Layout {
+        architecture: "gpt-j",
+        fields: 7,
+        vocabulary: 0,
+        context: Some(1),
+        embedding: 2,
+        layers: 4,
+        check: |h| divides(h[2], h[3]) && h[5] <= h[2] / h[3],
+    },
+    // n_vocab, n_ctx, n_embd, n_head, n_layer, ftype
+    Layout {
+        architecture: "gpt-2",
+        fields: 6,
+        vocabulary: 0,
+        context: Some(1),
+        embedding: 2,
+        layers: 4,
+        check: |h| divides(h[2], h[3]) && h[4] > 0,
+    },
+    // n_vocab, n_embd, n_mult, n_head, n_layer, ftype
+    Layout {
+        architecture: "bloom",
+        fields: 6,
+        vocabulary: 0,
+        context: None,
+        embedding: 1,
+        layers: 4,
+        check: |h| divides(h[1], h[3]) && h[4] > 0,
+    },
+];
+
+fn divides(a: u32, b: u32) -> bool {
+    a > 0 && b > 0 && a.is_multiple_of(b)
+}
+
+fn inspect_ggml<R: Read + Seek>(reader: &mut R) -> Result<Inspection> {
+    // Versioned containers store a score alongside every vocabulary token.
+    let scored = match read_u32(reader)? {
+        GGML_MAGIC => false,
+        GGMF_MAGIC | GGJT_MAGIC => {
+            read_u32(reader)?;
+            true
+        }
+        _ => return Err(anyhow!("Unrecognised model file format")),
+    };
+    let start = reader.stream_position()?;
+    for layout in LAYOUTS.iter() {
+        reader.seek(SeekFrom::Start(start))?;
+        if let Ok(Some(inspection)) = try_layout(reader, layout, scored) {
+            return Ok(inspection);
+        }
+    }
+    Ok(Inspection::default())
+}
+
+fn try_layout<R: Read + Seek>(
+    reader: &mut R,
+    layout: &Layout,
+    scored: bool,
+) -> Result<Option<Inspection>> {
+    let hyperparameters = (0..layout.fields)
+        .map(|_| read_u32(reader))
+        .collect::<Result<Vec<_>, _>>()?;
+    let vocabulary_size = hyperparameters[layout.vocabulary] as usize;
+    // Quantized files may have the quantization version folded into the file type.
+    let file_type = hyperparameters[layout.fields - 1] % 1000;
+    if vocabulary_size == 0 || vocabulary_size > 1_000_000 || !(layout.check)(&hyperparameters) {
+        return Ok(None);
+    }
+
+    // The hyperparameters only line up if the vocabulary and the first tensor
+    // that follow them also make sense.
+    for _ in 0..vocabulary_size {
+        let len = read_u32(reader)?;
+        if len > 1024 {
+            return Ok(None);
+        }
+        reader.seek(SeekFrom::Current(len as i64 + if scored { 4 } else { 0 }))?;
+    }
+    if !valid_tensor_header(reader)? {
+        return Ok(None);
+    }
+
+    let file_type = describe_file_type(file_type as u64);
+    Ok(Some(Inspection {
+        architecture: Some(layout.architecture.to_string()),
+        vocabulary_size: Some(vocabulary_size),
+        context_length: layout.context.map(|i| hyperparameters[i] as u64),
+        embedding_size: Some(hyperparameters[layout.embedding] as u64),
+        layers: Some(hyperparameters[layout.layers] as u64),
+        quantization: file_type.map(|(_, q)| q),
+        file_type: file_type.map(|(name, _)| name.to_string()),
+    }))
+}
+
+fn valid_tensor_header<R: Read>(reader: &mut R) -> Result<bool> {
+    let dimensions = read_u32(reader)?;
+    let name_len = read_u32(reader)?;
+    let element_type = read_u32(reader)?;
+    if !(1..=4).contains(&dimensions) || !(1..=512).contains(&name_len) || element_type > 32 {
+        return Ok(false);
+    }
+    for _ in 0..dimensions {
+        if read_u32(reader)? == 0 {
+            return Ok(false);
+        }
+    }
+    let mut name = vec![0u8; name_len as usize];
+    reader.read_exact(&mut name)?;
+    Ok(name.iter().all(|c| c.is_ascii_graphic()))
+}
+
+/// Maps a ggml file type to its name and the quantization we show in the interface.
+fn describe_file_type(file_type: u64) -> Option<(&'static str, Quantization)> {
+    Some(match file_type {
+        0 => ("F32", Quantization::None),
+        1 => ("F16", Quantization::None),
+        2 => ("Q4_0", Quantization::Bit4),
+        3 => ("Q4_1", Quantization::Bit4),
+        4 => ("Q4_1_SOME_F16", Quantization::Bit4),
+        7 => ("Q8_0", Quantization::Bit8),
+        8 => ("Q5_0", Quantization::Bit5),
+        9 => ("Q5_1", Quantization::Bit5),
+        10 => ("Q2_K", Quantization::Bit2),
+        11 => ("Q3_K_S", Quantization::Bit3),
+        12 => ("Q3_K_M", Quantization::Bit3),
+        13 => ("Q3_K_L", Quantization::Bit3),
+        14 => ("Q4_K_S", Quantization::Bit4),
+        15 => ("Q4_K_M", Quantization::Bit4),
+        16 => ("Q5_K_S", Quantization::Bit5),
+        17 => ("Q5_K_M", Quantization::Bit5),
+        18 => ("Q6_K", Quantization::Bit6),
+        _ => return None,
+    })
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inspect::inspect_ggml;
+    use std::io::Cursor;
+
+    fn ggml_file(magic: &[u8; 4], hyperparameters: &[u32], scored: bool) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(magic);
+        if scored {
+            buf.extend(3u32.to_le_bytes());
+        }
+        for h in hyperparameters {
+            buf.extend(h.to_le_bytes());
+        }
+        for token in ["<s>", "</s>", "hello"] {
+            buf.extend((token.len() as u32).to_le_bytes());
+            buf.extend(token.as_bytes());
+            if scored {
+                buf.extend(0f32.to_le_bytes());
+            }
+        }
+        let name = "tok_embeddings.weight";
+        for v in [2, name.len() as u32, 12, 4096, 3] {
+            buf.extend(v.to_le_bytes());
+        }
+        buf.extend(name.as_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_inspect_llama() {
+        let file = ggml_file(b"tjgg", &[3, 4096, 256, 32, 32, 128, 15], true);
+        let inspection = inspect_ggml(&mut Cursor::new(file)).unwrap();
+        assert_eq!(inspection.architecture.as_deref(), Some("llama"));
+        assert_eq!(inspection.vocabulary_size, Some(3));
+        assert_eq!(inspection.context_length, None);
+        assert_eq!(inspection.file_type.as_deref(), Some("Q4_K_M"));
+        // 32 layers of 4096 keys and values per position
+        assert_eq!(inspection.session_memory(2048), Some(1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_inspect_gpt_j() {
+        let file = ggml_file(b"lmgg", &[3, 2048, 4096, 16, 28, 64, 2002], false);
+        let inspection = inspect_ggml(&mut Cursor::new(file)).unwrap();
+        assert_eq!(inspection.architecture.as_deref(), Some("gpt-j"));
+        assert_eq!(inspection.context_length, Some(2048));
+        assert_eq!(inspection.file_type.as_deref(), Some("Q4_0"));
+    }
+}
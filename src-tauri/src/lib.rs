@@ -0,0 +1,20 @@
+//! The model and conversation backend, shared by the desktop app and the
+//! command-line interface.
+
+pub mod cache;
+pub mod cancellation;
+pub mod compare;
+pub mod config;
+pub mod context_file;
+pub mod conversations;
+pub mod inspect;
+pub mod models;
+pub mod overflow;
+pub mod prompt;
+pub mod sampling;
+pub mod settings;
+pub mod sidecar;
+pub mod snapshots;
+pub mod templates;
+#[cfg(test)]
+mod test_util;
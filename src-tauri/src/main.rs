@@ -7,37 +7,50 @@
 )]
 extern crate llm;
 
-mod config;
+mod downloads;
 mod events;
-mod models;
-
-mod cancellation;
-mod context_file;
-mod prompt;
+mod registry;
 #[cfg(target_os = "macos")]
 mod titlebar;
+mod worker;
 
-use crate::cancellation::Canceller;
-use crate::config::get_logs_dir;
+use crate::downloads::{Download, DownloadManager};
 use crate::events::Event;
-use crate::models::{get_local_model, Architecture, Model, ModelManager};
-use crate::prompt::Template;
+use crate::registry::{LoadedModel, ModelStatus, Registry, DEFAULT_SESSION};
 #[cfg(target_os = "macos")]
 use crate::titlebar::WindowExt;
+use crate::worker::Worker;
 use bytesize::ByteSize;
+use chitchat_lib::cancellation::{Cancellations, Canceller};
+use chitchat_lib::compare::{Comparison, Contender, MAX_PARALLEL};
+use chitchat_lib::config::{get_logs_dir, get_models_dir};
+use chitchat_lib::conversations::{Conversation, Exchange, Message, Role, Stats, Summary};
+use chitchat_lib::inspect::{inspect, Inspection};
+use chitchat_lib::models::{
+    get_local_model, Architecture, DiskUsage, DownloadCancelled, Model, ModelManager, TokenCount,
+};
+use chitchat_lib::overflow::{OverflowStrategy, RESPONSE_RESERVE};
+use chitchat_lib::prompt::{Template, Variables};
+use chitchat_lib::sampling::{Preset, SamplingSettings};
+use chitchat_lib::settings::Settings;
+use chitchat_lib::sidecar::Sidecar;
+use chitchat_lib::snapshots::Entry;
+use chitchat_lib::{
+    cache, compare, context_file, conversations, models, overflow, sampling, settings, sidecar,
+    snapshots, templates,
+};
 use llm::{InferenceResponse, LoadProgress};
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fs;
 use std::fs::create_dir_all;
-use std::path::PathBuf;
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tauri::{Manager, Window};
 use tauri_plugin_aptabase::EventTracker;
-use tracing::info;
+use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
-struct ManagerState(Mutex<Option<ModelManager>>);
-
 #[tauri::command]
 async fn get_models() -> Result<Vec<Model>, String> {
     models::get_available_models()
@@ -45,213 +58,1171 @@ async fn get_models() -> Result<Vec<Model>, String> {
         .map_err(|err| err.to_string())
 }
 
+#[tauri::command]
+fn get_disk_usage() -> Result<DiskUsage, String> {
+    models::get_disk_usage().map_err(|err| err.to_string())
+}
+
+/// Deletes a model that isn't loaded, stopping its download if there is one
+#[tauri::command]
+async fn delete_model(
+    window: Window,
+    worker: tauri::State<'_, Worker>,
+    downloads: tauri::State<'_, DownloadManager>,
+    filename: String,
+) -> Result<(), String> {
+    // Stop any background download first so it doesn't recreate the file.
+    if downloads.list().iter().any(|d| d.filename == filename) {
+        downloads
+            .remove(&window, &filename)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+    if worker.status().is_loaded(&filename) {
+        return Err("Model is loaded, unload it first".to_string());
+    }
+    models::delete_model(&filename).map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn inspect_model(filename: String) -> Result<Inspection, String> {
+    let path = models::model_path(&filename).map_err(|err| err.to_string())?;
+    inspect(&path).map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn get_model_metadata(filename: String) -> Result<Option<Sidecar>, String> {
+    sidecar::read(&filename).map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn save_model_metadata(filename: String, metadata: Sidecar) -> Result<(), String> {
+    sidecar::write(&filename, &metadata).map_err(|err| err.to_string())
+}
+
 #[tauri::command]
 fn get_architectures() -> Vec<Architecture> {
     models::AVAILABLE_ARCHITECTURES.clone()
 }
 
+/// Returns the built-in prompt templates followed by the user's own
 #[tauri::command]
-fn get_prompt_templates() -> Vec<Template> {
-    prompt::AVAILABLE_TEMPLATES.clone()
+fn get_prompt_templates() -> Result<Vec<Template>, String> {
+    templates::list().map_err(|err| err.to_string())
 }
 
 #[tauri::command]
-async fn cancel(canceller: tauri::State<'_, Canceller>) -> Result<(), String> {
-    canceller.cancel().await.map_err(|err| err.to_string())
+fn create_prompt_template(template: Template) -> Result<Template, String> {
+    templates::create(template).map_err(|err| err.to_string())
 }
 
+#[tauri::command]
+fn update_prompt_template(name: String, template: Template) -> Result<Template, String> {
+    templates::update(&name, template).map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn duplicate_prompt_template(name: String) -> Result<Template, String> {
+    templates::duplicate(&name).map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn delete_prompt_template(name: String) -> Result<(), String> {
+    templates::delete(&name).map_err(|err| err.to_string())
+}
+
+/// Imports the templates in the provided file, returning the ones that were added
+#[tauri::command]
+fn import_prompt_templates(path: String) -> Result<Vec<Template>, String> {
+    templates::import(&PathBuf::from(path)).map_err(|err| err.to_string())
+}
+
+/// Exports the templates with the provided names, or all user templates
+#[tauri::command]
+fn export_prompt_templates(path: String, names: Vec<String>) -> Result<(), String> {
+    templates::export(&PathBuf::from(path), &names).map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn get_sampling_presets() -> Vec<Preset> {
+    sampling::AVAILABLE_PRESETS.clone()
+}
+
+/// Tokenizes the text with the loaded model, e.g. to check how much of the
+/// context window a draft message would take up
+#[tauri::command]
+async fn count_tokens(
+    worker: tauri::State<'_, Worker>,
+    session: Option<String>,
+    text: String,
+) -> Result<TokenCount, String> {
+    let status = worker.status();
+    let session = status
+        .session(session.as_deref())
+        .map_err(|err| err.to_string())?;
+    TokenCount::new(session.model.as_ref(), session.used, &text).map_err(|err| err.to_string())
+}
+
+/// The job key that model comparisons are cancelled by
+const COMPARISON_JOB: &str = "comparison";
+
+/// Stops the jobs running or queued for a session, without waiting for them.
+/// Loading a model on its own is cancelled by its filename, and comparisons
+/// by [`COMPARISON_JOB`].
+#[tauri::command]
+fn cancel(cancellations: tauri::State<'_, Cancellations>, job: Option<String>) {
+    cancellations.cancel(job.as_deref().unwrap_or(DEFAULT_SESSION));
+}
+
+#[tauri::command]
+fn list_downloads(downloads: tauri::State<'_, DownloadManager>) -> Vec<Download> {
+    downloads.list()
+}
+
+#[tauri::command]
+fn enqueue_download(
+    window: Window,
+    downloads: tauri::State<'_, DownloadManager>,
+    filename: String,
+) -> Result<(), String> {
+    downloads
+        .enqueue(&window, &filename)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn pause_download(
+    window: Window,
+    downloads: tauri::State<'_, DownloadManager>,
+    filename: String,
+) -> Result<(), String> {
+    downloads
+        .pause(&window, &filename)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn resume_download(
+    window: Window,
+    downloads: tauri::State<'_, DownloadManager>,
+    filename: String,
+) -> Result<(), String> {
+    downloads
+        .resume(&window, &filename)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn remove_download(
+    window: Window,
+    downloads: tauri::State<'_, DownloadManager>,
+    filename: String,
+) -> Result<(), String> {
+    downloads
+        .remove(&window, &filename)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Finds the model file, downloading it first if it's a catalog model that
+/// isn't on disk yet. Returns `None` if the download was cancelled. The
+/// download is claimed with the download manager while it runs, so that the
+/// same model can't be downloaded in the background at the same time.
+async fn locate(
+    window: &Window,
+    downloads: &DownloadManager,
+    canceller: &Canceller,
+    model_filename: &str,
+) -> Result<Option<PathBuf>, String> {
+    let models_dir = get_models_dir().map_err(|err| err.to_string())?;
+    let _claim = if models_dir.join(model_filename).exists() {
+        None
+    } else {
+        Some(
+            downloads
+                .claim(model_filename)
+                .map_err(|err| err.to_string())?,
+        )
+    };
+    match get_local_model(model_filename, canceller, |downloaded, total, progress| {
+        let message = format!(
+            "Downloading model ({} / {})",
+            ByteSize(downloaded),
+            ByteSize(total)
+        );
+        Event::ModelLoading { message, progress }.send(window);
+    })
+    .await
+    {
+        Ok(path) => Ok(Some(path)),
+        Err(err) if err.is::<DownloadCancelled>() => {
+            Event::DownloadCancelled {
+                filename: model_filename.to_string(),
+            }
+            .send(window);
+            Ok(None)
+        }
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Loads the model file unless it's already loaded with the same settings,
+/// refusing to go over the memory budget. When the model is loaded for a
+/// session, the memory that session will take up is reserved as well, counting
+/// whatever the session it replaces takes up as free. The replaced session is
+/// left alone so that it survives a failed load, and is only closed once the
+/// model has loaded if it ran on the same model with other settings.
+#[allow(clippy::too_many_arguments)]
+fn load<'a>(
+    window: &Window,
+    registry: &'a mut Registry,
+    path: &Path,
+    model_filename: &str,
+    architecture: &str,
+    tokenizer: &str,
+    context_size: usize,
+    use_gpu: bool,
+    session: Option<&str>,
+) -> Result<&'a mut LoadedModel, String> {
+    let session_memory = inspect(path)
+        .ok()
+        .and_then(|inspection| inspection.session_memory(context_size))
+        .unwrap_or_else(|| {
+            warn!("couldn't estimate the session memory of the model");
+            0
+        });
+    let loaded = registry
+        .find(model_filename, context_size, use_gpu)
+        .is_some();
+    // The same model can't be loaded twice, so it's only reloaded with other
+    // settings when nothing but the session being replaced needs it.
+    if !loaded && !registry.replaceable(model_filename, session) {
+        return Err(format!(
+            "{} is already loaded with different settings, unload it first",
+            model_filename
+        ));
+    }
+    let memory = fs::metadata(path).map_err(|err| err.to_string())?.len();
+    let needed = if loaded { 0 } else { memory } + session.map_or(0, |_| session_memory);
+    registry
+        .reserve(needed, session, model_filename)
+        .map_err(|err| err.to_string())?;
+
+    if !loaded {
+        info!(
+            gpu = use_gpu,
+            model = path.to_str().unwrap_or_default(),
+            "loading model"
+        );
+
+        let params = llm::ModelParameters {
+            use_gpu,
+            context_size,
+            ..Default::default()
+        };
+        let model = models::load(path, architecture, tokenizer, params, |progress| {
+            match progress {
+                LoadProgress::HyperparametersLoaded => Event::ModelLoading {
+                    message: "Hyper-parameters loaded".to_string(),
+                    progress: 0.05,
+                }
+                .send(&window),
+                LoadProgress::ContextSize { .. } => Event::ModelLoading {
+                    message: "Context created".to_string(),
+                    progress: 0.1,
+                }
+                .send(&window),
+                LoadProgress::LoraApplied { .. } => Event::ModelLoading {
+                    message: "LoRA applied".to_string(),
+                    progress: 0.15,
+                }
+                .send(&window),
+                LoadProgress::TensorLoaded {
+                    current_tensor,
+                    tensor_count,
+                } => {
+                    // Once we start loading tensors, we're at 20%, once we're finished,
+                    // we're at 50% and intermediate tensor loads should be linearly
+                    // interpolated.
+                    let start = 0.2;
+                    let end = 0.5;
+                    let progress =
+                        start + (end - start) * (current_tensor as f32 / tensor_count as f32);
+                    Event::ModelLoading {
+                        message: format!("Loading tensor {}/{}", current_tensor, tensor_count),
+                        progress,
+                    }
+                    .send(&window)
+                }
+                LoadProgress::Loaded { .. } => Event::ModelLoading {
+                    message: "Model loaded".to_string(),
+                    progress: 0.6,
+                }
+                .send(&window),
+            }
+        })
+        .map_err(|err| err.to_string())?;
+        // Only now that the model is loaded does the session running on it with
+        // other settings make way, taking the old model with it.
+        if let Some(session) = session.filter(|_| registry.is_loaded(model_filename)) {
+            let _ = registry.close(session);
+        }
+        registry
+            .insert(LoadedModel {
+                id: model_filename.to_string(),
+                model: Arc::from(model),
+                context_size,
+                use_gpu,
+                memory,
+                session_memory,
+                pinned: false,
+            })
+            .map_err(|err| err.to_string())?;
+    }
+    registry
+        .find(model_filename, context_size, use_gpu)
+        .ok_or("Model not loaded".to_string())
+}
+
+#[allow(clippy::too_many_arguments)]
 #[tauri::command]
 async fn start(
     window: Window,
-    state: tauri::State<'_, ManagerState>,
-    canceller: tauri::State<'_, Canceller>,
+    worker: tauri::State<'_, Worker>,
+    cancellations: tauri::State<'_, Cancellations>,
+    downloads: tauri::State<'_, DownloadManager>,
     model_filename: String,
     architecture: String,
     tokenizer: String,
     context_size: usize,
     use_gpu: bool,
-    prompt: Template,
+    prompt: Option<Template>,
     context_files: Vec<String>,
-) -> Result<bool, String> {
-    canceller.reset();
-    let context = context_files
+    sampling: Option<SamplingSettings>,
+    max_tokens: Option<usize>,
+    seed: Option<u64>,
+    conversation_id: Option<String>,
+    variables: Option<BTreeMap<String, String>>,
+    overflow: Option<OverflowStrategy>,
+    session: Option<String>,
+) -> Result<Option<String>, String> {
+    let session_id = session.unwrap_or_else(|| DEFAULT_SESSION.to_string());
+    let canceller = cancellations.register(&session_id);
+
+    // The catalog or the model's sidecar provides defaults for anything that
+    // wasn't set explicitly.
+    let defaults = models::find_model(&model_filename)
+        .await
+        .map_err(|err| err.to_string())?;
+    let prompt = match prompt {
+        Some(prompt) => prompt,
+        None => templates::default_for(defaults.as_ref().and_then(|m| m.template.as_deref()))
+            .map_err(|err| err.to_string())?,
+    };
+
+    let contents = context_files
         .iter()
         .map(|path| context_file::read(PathBuf::from(path)))
         .collect::<Result<Vec<_>, _>>()
-        .map_err(|err| err.to_string())?
-        .join("\n");
+        .map_err(|err| err.to_string())?;
+    let context = contents.join("\n");
 
-    let warmup_prompt = if !context.is_empty() {
-        format!("{}\n{}", context, prompt.warmup)
-    } else {
-        prompt.warmup.clone()
+    prompt.check().map_err(|err| err.to_string())?;
+    let variables = Variables::new(
+        &prompt,
+        defaults.as_ref().map_or(&model_filename, |m| &m.name),
+        context,
+        variables.unwrap_or_default(),
+    );
+
+    // When continuing a conversation, the model needs to see everything that
+    // was said so far.
+    let (conversation, resumable) = match conversation_id {
+        Some(id) => {
+            let mut conversation = conversations::load(&id).map_err(|err| err.to_string())?;
+            // A session snapshot is only valid if nothing about the set-up changed
+            let resumable = conversation.model == model_filename
+                && conversation.template == prompt
+                && conversation.context_files == context_files;
+            conversation.model = model_filename.clone();
+            conversation.template = prompt.clone();
+            conversation.context_files = context_files.clone();
+            (conversation, resumable)
+        }
+        None => (
+            Conversation::new(&model_filename, &prompt, &context_files),
+            false,
+        ),
     };
+    let system = prompt
+        .system(&variables, &conversation.messages)
+        .map_err(|err| err.to_string())?;
+    // Whatever had been dropped from the context window stays dropped.
+    let warmup_prompt = prompt
+        .render(
+            &overflow::system_prompt(&system, conversation.summary.as_deref()),
+            &variables,
+            &conversation.messages[conversation.dropped..],
+        )
+        .map_err(|err| err.to_string())?;
 
-    let path = get_local_model(&model_filename, |downloaded, total, progress| {
-        let message = format!(
-            "Downloading model ({} / {})",
-            ByteSize(downloaded),
-            ByteSize(total)
-        );
-        Event::ModelLoading { message, progress }.send(&window);
-    })
-    .await
-    .map_err(|err| err.to_string())?;
-    let architecture = models::AVAILABLE_ARCHITECTURES
-        .iter()
-        .find(|v| *v.id == architecture)
-        .ok_or("Architecture not found")?;
-    let tokenizer = match tokenizer.as_str() {
-        "embedded" => llm::TokenizerSource::Embedded,
-        _ => return Err("Tokenizer not supported".to_string()),
+    let Some(path) = locate(&window, &downloads, &canceller, &model_filename).await? else {
+        return Ok(None);
     };
+    // Fall back to the model's own defaults if no sampling settings were provided
+    let sampling = sampling
+        .or_else(|| defaults.and_then(|m| m.sampling))
+        .unwrap_or_default();
+    sampling.validate().map_err(|err| err.to_string())?;
 
-    info!(
-        gpu = use_gpu,
-        model = path.to_str().unwrap_or_default(),
-        "starting model"
-    );
+    // Loading and warming up the model are blocking, so they happen on the
+    // inference worker once it's done with anything queued before them.
+    worker
+        .run(move |registry| -> Result<Option<String>, String> {
+            let model = load(
+                &window,
+                registry,
+                &path,
+                &model_filename,
+                &architecture,
+                &tokenizer,
+                context_size,
+                use_gpu,
+                Some(&session_id),
+            )?
+            .model
+            .clone();
 
-    let params = llm::ModelParameters {
-        use_gpu,
-        context_size,
-        ..Default::default()
-    };
-    let model = llm::load_dynamic(
-        Some(architecture.inner),
-        path.as_path(),
-        tokenizer,
-        params,
-        |progress| match progress {
-            LoadProgress::HyperparametersLoaded => Event::ModelLoading {
-                message: "Hyper-parameters loaded".to_string(),
-                progress: 0.05,
-            }
-            .send(&window),
-            LoadProgress::ContextSize { .. } => Event::ModelLoading {
-                message: "Context created".to_string(),
-                progress: 0.1,
-            }
-            .send(&window),
-            LoadProgress::LoraApplied { .. } => Event::ModelLoading {
-                message: "LoRA applied".to_string(),
-                progress: 0.15,
-            }
-            .send(&window),
-            LoadProgress::TensorLoaded {
-                current_tensor,
-                tensor_count,
-            } => {
-                // Once we start loading tensors, we're at 20%, once we're finished, we're at 50%
-                // and intermediate tensor loads should be linearly interpolated.
-                let start = 0.2;
-                let end = 0.5;
-                let progress =
-                    start + (end - start) * (current_tensor as f32 / tensor_count as f32);
-                Event::ModelLoading {
-                    message: format!("Loading tensor {}/{}", current_tensor, tensor_count),
-                    progress,
+            let manager = (|| -> Result<Option<ModelManager>, String> {
+                // Let the interface know how much of the context window each file takes up
+                for (path, content) in context_files.iter().zip(&contents) {
+                    match overflow::count_tokens(model.as_ref(), content) {
+                        Ok(tokens) => {
+                            info!(path, tokens, "counted context file tokens");
+                            Event::ContextFileTokens {
+                                path: path.clone(),
+                                tokens,
+                            }
+                            .send(&window);
+                        }
+                        Err(err) => warn!(
+                            error = err.to_string(),
+                            path, "counting context file tokens"
+                        ),
+                    }
                 }
-                .send(&window)
-            }
-            LoadProgress::Loaded { .. } => Event::ModelLoading {
-                message: "Model loaded".to_string(),
-                progress: 0.6,
-            }
-            .send(&window),
-        },
-    )
-    .map_err(|e| format!("Error loading model: {}", e))?;
-
-    let mut session = model.start_session(Default::default());
-
-    // When you feed a prompt, progress is going to be determined by how far
-    // through repeating the warmup prompt we are.
-    let mut progress_length = 0;
-    session
-        .feed_prompt(
-            model.as_ref(),
-            warmup_prompt.as_str(),
-            &mut Default::default(),
-            llm::feed_prompt_callback(|res| match res {
-                InferenceResponse::PromptToken(t) => {
-                    progress_length += t.len();
-                    let progress = progress_length as f32 / warmup_prompt.len() as f32;
-                    Event::ModelLoading {
-                        message: format!("Warming up model ({:.0}%)", progress * 100.0),
-                        progress,
+
+                // Restoring a snapshot of the conversation skips warming up entirely.
+                let snapshot = conversation.head.map(|head| conversation.snapshot_id(head));
+                let restored = match snapshot.filter(|id| resumable && snapshots::exists(id)) {
+                    Some(id) => match snapshots::restore(&id, &model_filename, model.as_ref()) {
+                        Ok(session) => Some(session),
+                        Err(err) => {
+                            warn!(
+                                error = err.to_string(),
+                                "restoring session snapshot, warming up instead"
+                            );
+                            None
+                        }
+                    },
+                    None => None,
+                };
+
+                // A long conversation may not fit in the context window anymore, in which
+                // case the overflow strategy decides what happens to its oldest messages
+                // once the session has been set up.
+                let budget = context_size.saturating_sub(max_tokens.unwrap_or(RESPONSE_RESERVE));
+                let overflowed = restored.is_none()
+                    && !conversation.messages.is_empty()
+                    && overflow::count_tokens(model.as_ref(), &warmup_prompt)
+                        .map_err(|err| err.to_string())?
+                        > budget;
+
+                // New conversations start from the same warm-up prompt every time, so we
+                // can reuse a session that was warmed up earlier with the same set-up.
+                let cache_key = if restored.is_none() && conversation.messages.is_empty() {
+                    cache::key(&path, context_size, &warmup_prompt)
+                        .map_err(|err| warn!(error = err.to_string(), "computing cache key"))
+                        .ok()
+                } else {
+                    None
+                };
+                let cached = match &cache_key {
+                    Some(key) => cache::restore(key, &model_filename, model.as_ref())
+                        .unwrap_or_else(|err| {
+                            warn!(error = err.to_string(), "restoring cached session");
+                            None
+                        }),
+                    None => None,
+                };
+
+                let session = match restored.or(cached) {
+                    Some(session) => session,
+                    // Checking out the conversation below warms up a new one.
+                    None if overflowed => model.start_session(Default::default()),
+                    None => {
+                        let tokens = overflow::tokenize(model.as_ref(), &warmup_prompt, true)
+                            .map_err(|err| err.to_string())?;
+                        let mut session = model.start_session(Default::default());
+
+                        // When you feed a prompt, progress is going to be determined by how far
+                        // through repeating the warmup prompt we are.
+                        let mut progress_length = 0;
+                        session
+                            .feed_prompt(
+                                model.as_ref(),
+                                &tokens,
+                                &mut Default::default(),
+                                llm::feed_prompt_callback(|res| match res {
+                                    InferenceResponse::PromptToken(t) => {
+                                        progress_length += t.len();
+                                        let progress =
+                                            progress_length as f32 / warmup_prompt.len() as f32;
+                                        Event::ModelLoading {
+                                            message: format!(
+                                                "Warming up model ({:.0}%)",
+                                                progress * 100.0
+                                            ),
+                                            progress,
+                                        }
+                                        .send(&window);
+                                        canceller.inference_feedback()
+                                    }
+                                    _ => canceller.inference_feedback(),
+                                }),
+                            )
+                            .map_err(|e| format!("Error feeding prompt: {}", e))?;
+                        if let (Some(key), false) = (&cache_key, canceller.is_cancelled()) {
+                            if let Err(err) = cache::save(key, &model_filename, &mut session) {
+                                error!(error = err.to_string(), "caching warmed up session");
+                            }
+                        }
+                        session
                     }
-                    .send(&window);
-                    canceller.inference_feedback()
+                };
+                Event::ModelLoading {
+                    message: "Model loaded".to_string(),
+                    progress: 1.0,
                 }
-                _ => canceller.inference_feedback(),
-            }),
-        )
-        .map_err(|e| format!("Error feeding prompt: {}", e))?;
-    Event::ModelLoading {
-        message: "Model loaded".to_string(),
-        progress: 1.0,
-    }
-    .send(&window);
+                .send(&window);
 
-    if canceller.is_cancelled() {
-        return Ok(false);
-    }
+                if canceller.is_cancelled() {
+                    return Ok(None);
+                }
 
-    info!("finished warm-up prompt");
-    *state.0.lock().unwrap() = Some(ModelManager {
-        model,
-        session,
-        template: prompt,
-    });
+                let mut manager = ModelManager {
+                    model_id: model_filename.clone(),
+                    model,
+                    session,
+                    template: prompt,
+                    system,
+                    variables,
+                    sampling,
+                    maximum_token_count: max_tokens,
+                    seed,
+                    conversation,
+                    overflow: overflow.unwrap_or_default(),
+                };
+                if overflowed {
+                    let head = manager.conversation.head;
+                    if let Some(overflow) = manager.checkout(head).map_err(|err| err.to_string())? {
+                        Event::ContextOverflow {
+                            strategy: overflow.strategy,
+                            dropped: overflow.dropped,
+                            summary: overflow.summary,
+                        }
+                        .send(&window);
+                    }
+                }
+                info!("finished warm-up prompt");
+                Ok(Some(manager))
+            })();
 
-    Ok(true)
+            // A model that was loaded for nothing doesn't stay loaded
+            match manager {
+                Ok(Some(manager)) => {
+                    registry.open(&session_id, manager);
+                    Ok(Some(session_id))
+                }
+                result => {
+                    registry.release(&model_filename);
+                    result.map(|_| None)
+                }
+            }
+        })
+        .await
+        .map_err(|err| err.to_string())?
 }
 
 #[derive(Serialize)]
 pub struct PromptResponse {
     pub stats: llm::InferenceStats,
     pub message: String,
+    /// The seed the response was generated with, which can be passed back
+    /// in to reproduce it exactly
+    pub seed: u64,
+    /// The id of the conversation the response was recorded in
+    pub conversation: String,
+    /// The id of the exchange within the conversation
+    pub exchange: usize,
 }
 
-#[tracing::instrument(skip(window, state, canceller, message))]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(window, worker, cancellations, message))]
 #[tauri::command]
 async fn prompt(
     window: Window,
-    state: tauri::State<'_, ManagerState>,
-    canceller: tauri::State<'_, Canceller>,
+    worker: tauri::State<'_, Worker>,
+    session: Option<String>,
+    cancellations: tauri::State<'_, Cancellations>,
     message: String,
+    sampling: Option<SamplingSettings>,
+    max_tokens: Option<usize>,
+    seed: Option<u64>,
 ) -> Result<PromptResponse, String> {
     info!("received prompt");
+    if let Some(sampling) = &sampling {
+        sampling.validate().map_err(|err| err.to_string())?;
+    }
+    let canceller = cancellations.register(session.as_deref().unwrap_or(DEFAULT_SESSION));
+    worker
+        .with_session(session, move |manager| {
+            let seed = seed.or(manager.seed);
+            respond(
+                &window, manager, &canceller, message, sampling, max_tokens, seed,
+            )
+        })
+        .await
+}
+
+/// Goes back to before the last exchange, returning the user's message so that
+/// it can be edited. The exchange is kept as a branch of its own.
+#[tracing::instrument(skip(worker))]
+#[tauri::command]
+async fn undo(
+    worker: tauri::State<'_, Worker>,
+    session: Option<String>,
+) -> Result<Message, String> {
+    worker
+        .with_session(session, |manager| {
+            let message = manager.rewind().map_err(|err| err.to_string())?;
+            persist(manager);
+            Ok(message)
+        })
+        .await
+}
+
+/// Replaces the response to the last message with a new one. Unless a seed is
+/// provided, a new random one is picked so that the response actually changes.
+#[tracing::instrument(skip(window, worker, cancellations))]
+#[tauri::command]
+async fn regenerate(
+    window: Window,
+    worker: tauri::State<'_, Worker>,
+    session: Option<String>,
+    cancellations: tauri::State<'_, Cancellations>,
+    sampling: Option<SamplingSettings>,
+    max_tokens: Option<usize>,
+    seed: Option<u64>,
+) -> Result<PromptResponse, String> {
+    if let Some(sampling) = &sampling {
+        sampling.validate().map_err(|err| err.to_string())?;
+    }
+    let canceller = cancellations.register(session.as_deref().unwrap_or(DEFAULT_SESSION));
+    worker
+        .with_session(session, move |manager| {
+            let message = manager.rewind().map_err(|err| err.to_string())?;
+            respond(
+                &window,
+                manager,
+                &canceller,
+                message.content,
+                sampling,
+                max_tokens,
+                seed,
+            )
+        })
+        .await
+}
+
+/// Replaces the last message and responds to the new one instead
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(window, worker, cancellations, message))]
+#[tauri::command]
+async fn edit_last_message(
+    window: Window,
+    worker: tauri::State<'_, Worker>,
+    session: Option<String>,
+    cancellations: tauri::State<'_, Cancellations>,
+    message: String,
+    sampling: Option<SamplingSettings>,
+    max_tokens: Option<usize>,
+    seed: Option<u64>,
+) -> Result<PromptResponse, String> {
+    if let Some(sampling) = &sampling {
+        sampling.validate().map_err(|err| err.to_string())?;
+    }
+    let canceller = cancellations.register(session.as_deref().unwrap_or(DEFAULT_SESSION));
+    worker
+        .with_session(session, move |manager| {
+            manager.rewind().map_err(|err| err.to_string())?;
+            let seed = seed.or(manager.seed);
+            respond(
+                &window, manager, &canceller, message, sampling, max_tokens, seed,
+            )
+        })
+        .await
+}
 
-    let mut binding = state
-        .0
-        .lock()
-        .map_err(|e| format!("Unable to lock the backend: {e}"))?;
-    let manager: &mut ModelManager = (*binding).as_mut().ok_or("Model not started".to_string())?;
+/// Generates a response to the message, streaming it to the window, and
+/// records the exchange in the conversation.
+fn respond(
+    window: &Window,
+    manager: &mut ModelManager,
+    canceller: &Canceller,
+    message: String,
+    sampling: Option<SamplingSettings>,
+    max_tokens: Option<usize>,
+    seed: Option<u64>,
+) -> Result<PromptResponse, String> {
     let mut response = String::new();
+    // Random seeds are kept within 53 bits so they survive the round trip
+    // through JavaScript numbers without losing precision.
+    let seed = seed.unwrap_or_else(|| rand::random::<u64>() >> 11);
+    info!(seed, "generating response");
 
-    let stats = manager.infer(&message, |res| match res {
-        InferenceResponse::InferredToken(tokens) => {
-            response.push_str(&tokens);
-            Event::PromptResponse { message: tokens }.send(&window);
-            canceller.inference_feedback()
+    if let Some(overflow) = manager
+        .fit(&message, max_tokens)
+        .map_err(|err| err.to_string())?
+    {
+        Event::ContextOverflow {
+            strategy: overflow.strategy,
+            dropped: overflow.dropped,
+            summary: overflow.summary,
         }
-        _ => canceller.inference_feedback(),
-    })?;
+        .send(window);
+    }
+
+    let stats = manager.infer(
+        &message,
+        sampling.as_ref(),
+        max_tokens,
+        seed,
+        |res| match res {
+            InferenceResponse::InferredToken(tokens) => {
+                response.push_str(&tokens);
+                Event::PromptResponse { message: tokens }.send(window);
+                canceller.inference_feedback()
+            }
+            _ => canceller.inference_feedback(),
+        },
+    )?;
 
     info!("finished prompt response");
     Event::PromptResponse {
         message: Default::default(),
     }
-    .send(&window);
+    .send(window);
+
+    let response = response.replace(&message, "").trim().to_string();
+    let exchange = manager.conversation.push(
+        Message {
+            role: Role::User,
+            content: message,
+            stats: None,
+            seed: None,
+        },
+        Message {
+            role: Role::Assistant,
+            content: response.clone(),
+            stats: Some(Stats::from(&stats)),
+            seed: Some(seed),
+        },
+    );
+    persist(manager);
 
     Ok(PromptResponse {
         stats,
-        message: response.replace(&message, "").trim().to_string(),
+        message: response,
+        seed,
+        conversation: manager.conversation.id.clone(),
+        exchange,
     })
 }
 
+/// Saves the conversation along with a snapshot of the session, unless the
+/// current exchange already has one, so that the conversation can be picked
+/// up from there later.
+fn persist(manager: &mut ModelManager) {
+    if let Err(err) = conversations::save(&manager.conversation) {
+        error!(error = err.to_string(), "saving conversation");
+    }
+    let Some(head) = manager.conversation.head else {
+        return;
+    };
+    let id = manager.conversation.snapshot_id(head);
+    if snapshots::exists(&id) {
+        return;
+    }
+    if let Err(err) = snapshots::save(&id, &manager.model_id, &mut manager.session) {
+        error!(error = err.to_string(), "saving session snapshot");
+    }
+}
+
+#[tauri::command]
+async fn list_conversations() -> Result<Vec<Summary>, String> {
+    conversations::list().map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+async fn open_conversation(id: String) -> Result<Conversation, String> {
+    conversations::load(&id).map_err(|err| err.to_string())
+}
+
+/// Renames the conversation right away, and the sessions holding it once
+/// they're done with whatever is queued. A session saving the conversation in
+/// the meantime writes its old title back, so it's renamed again afterwards.
+#[tauri::command]
+fn rename_conversation(
+    worker: tauri::State<'_, Worker>,
+    id: String,
+    title: String,
+) -> Result<Conversation, String> {
+    let conversation = conversations::rename(&id, &title).map_err(|err| err.to_string())?;
+    worker
+        .queue(move |registry| {
+            let mut held = false;
+            // Keep the active conversation in sync so the next prompt doesn't undo the rename
+            for manager in registry.sessions_mut() {
+                if manager.conversation.id == id {
+                    manager.conversation.title = title.clone();
+                    held = true;
+                }
+            }
+            if held {
+                if let Err(err) = conversations::rename(&id, &title) {
+                    error!(error = err.to_string(), "failed to rename conversation");
+                }
+            }
+        })
+        .map_err(|err| err.to_string())?;
+    Ok(conversation)
+}
+
+/// Deletes the conversation right away, and lets the sessions holding it
+/// start a new one once they're done with whatever is queued. A session
+/// saving the conversation in the meantime writes it back, so it's deleted
+/// again afterwards.
+#[tauri::command]
+fn delete_conversation(worker: tauri::State<'_, Worker>, id: String) -> Result<(), String> {
+    conversations::delete(&id).map_err(|err| err.to_string())?;
+    snapshots::delete(&id).map_err(|err| err.to_string())?;
+    worker
+        .queue(move |registry| {
+            let mut held = false;
+            // Otherwise the next prompt would write the deleted conversation back to disk
+            for manager in registry.sessions_mut() {
+                if manager.conversation.id == id {
+                    let conversation = &manager.conversation;
+                    manager.conversation = Conversation::new(
+                        &conversation.model,
+                        &conversation.template,
+                        &conversation.context_files,
+                    );
+                    held = true;
+                }
+            }
+            if held && conversations::load(&id).is_ok() {
+                let deleted = conversations::delete(&id).and_then(|_| snapshots::delete(&id));
+                if let Err(err) = deleted {
+                    error!(error = err.to_string(), "failed to delete conversation");
+                }
+            }
+        })
+        .map_err(|err| err.to_string())
+}
+
+/// Switches the session over to the provided conversation, which has to have
+/// been held with the loaded model, restoring the snapshot of its last
+/// exchange so that it can be continued without warming up.
+#[tauri::command]
+async fn restore_snapshot(
+    worker: tauri::State<'_, Worker>,
+    session: Option<String>,
+    id: String,
+) -> Result<Conversation, String> {
+    let conversation = conversations::load(&id).map_err(|err| err.to_string())?;
+    worker
+        .with_session(session, move |manager| {
+            manager
+                .resume(conversation)
+                .map_err(|err| err.to_string())?;
+            Ok(manager.conversation.clone())
+        })
+        .await
+}
+
+/// Returns the provided exchange along with its alternatives, so that the
+/// interface can offer to switch between them
+#[tauri::command]
+async fn list_siblings(
+    worker: tauri::State<'_, Worker>,
+    session: Option<String>,
+    exchange: usize,
+) -> Result<Vec<Exchange>, String> {
+    worker
+        .with_session(session, move |manager| {
+            manager
+                .conversation
+                .siblings(exchange)
+                .map_err(|err| err.to_string())
+        })
+        .await
+}
+
+/// Switches to the most recent branch that goes through the provided exchange
+#[tracing::instrument(skip(worker))]
+#[tauri::command]
+async fn switch_branch(
+    worker: tauri::State<'_, Worker>,
+    session: Option<String>,
+    exchange: usize,
+) -> Result<Conversation, String> {
+    worker
+        .with_session(session, move |manager| {
+            let tip = manager.conversation.tip(exchange);
+            manager.checkout(Some(tip)).map_err(|err| err.to_string())?;
+            persist(manager);
+            Ok(manager.conversation.clone())
+        })
+        .await
+}
+
+/// Continues the conversation from the provided exchange, or from the very
+/// start if there is none. The next prompt starts a new branch there.
+#[tracing::instrument(skip(worker))]
+#[tauri::command]
+async fn fork(
+    worker: tauri::State<'_, Worker>,
+    session: Option<String>,
+    exchange: Option<usize>,
+) -> Result<Conversation, String> {
+    worker
+        .with_session(session, move |manager| {
+            manager.checkout(exchange).map_err(|err| err.to_string())?;
+            persist(manager);
+            Ok(manager.conversation.clone())
+        })
+        .await
+}
+
+/// Sends the same message to several loaded models, streaming each response
+/// tagged with the model's id so that they can be compared side by side.
+/// Each model gets the prompt template it would use by default, unless one
+/// is provided.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(window, worker, cancellations, message))]
+#[tauri::command]
+async fn compare_models(
+    window: Window,
+    worker: tauri::State<'_, Worker>,
+    cancellations: tauri::State<'_, Cancellations>,
+    model_ids: Vec<String>,
+    message: String,
+    prompt: Option<Template>,
+    context_files: Vec<String>,
+    variables: Option<BTreeMap<String, String>>,
+    sampling: Option<SamplingSettings>,
+    max_tokens: Option<usize>,
+    seed: Option<u64>,
+    parallel: Option<bool>,
+) -> Result<Vec<Comparison>, String> {
+    if model_ids.len() < 2 {
+        return Err("Pick at least two models to compare".to_string());
+    }
+    let context = context_files
+        .iter()
+        .map(|path| context_file::read(PathBuf::from(path)))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| err.to_string())?
+        .join("\n");
+
+    let mut setups = vec![];
+    for id in model_ids {
+        let defaults = models::find_model(&id)
+            .await
+            .map_err(|err| err.to_string())?;
+        let template = match &prompt {
+            Some(prompt) => prompt.clone(),
+            None => templates::default_for(defaults.as_ref().and_then(|m| m.template.as_deref()))
+                .map_err(|err| err.to_string())?,
+        };
+        template.check().map_err(|err| err.to_string())?;
+        let variables = Variables::new(
+            &template,
+            defaults.as_ref().map_or(&id, |m| &m.name),
+            context.clone(),
+            variables.clone().unwrap_or_default(),
+        );
+        // Models fall back to their own defaults if no sampling settings were provided
+        let sampling = sampling
+            .clone()
+            .or_else(|| defaults.and_then(|m| m.sampling))
+            .unwrap_or_default();
+        sampling.validate().map_err(|err| err.to_string())?;
+        setups.push((id, template, variables, sampling));
+    }
+    // Every model gets the same seed so that only the models differ
+    let seed = seed.unwrap_or_else(|| rand::random::<u64>() >> 11);
+
+    let canceller = cancellations.register(COMPARISON_JOB);
+    worker
+        .run(move |registry| -> anyhow::Result<Vec<Comparison>> {
+            let contenders = setups
+                .into_iter()
+                .map(|(id, template, variables, sampling)| {
+                    Ok(Contender {
+                        model: registry.model(&id)?,
+                        id,
+                        template,
+                        variables,
+                        sampling,
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            // Each contender responds in a fresh session, so only as many of
+            // them run at once as there is memory for.
+            let ids = contenders.iter().map(|c| c.id.as_str()).collect::<Vec<_>>();
+            let most = if parallel.unwrap_or_default() {
+                MAX_PARALLEL
+            } else {
+                1
+            };
+            let parallelism = registry.fitting(&ids, most)?;
+            compare::run(
+                &contenders,
+                &message,
+                max_tokens,
+                seed,
+                parallelism,
+                &canceller,
+                |model, message| {
+                    Event::ComparisonResponse {
+                        model: model.to_string(),
+                        message,
+                    }
+                    .send(&window)
+                },
+            )
+        })
+        .await
+        .and_then(|result| result)
+        .map_err(|err| err.to_string())
+}
+
+/// Loads a model and keeps it loaded until it's unloaded, even while no
+/// session is running on it
+#[allow(clippy::too_many_arguments)]
+#[tauri::command]
+async fn load_model(
+    window: Window,
+    worker: tauri::State<'_, Worker>,
+    cancellations: tauri::State<'_, Cancellations>,
+    downloads: tauri::State<'_, DownloadManager>,
+    model_filename: String,
+    architecture: String,
+    tokenizer: String,
+    context_size: usize,
+    use_gpu: bool,
+) -> Result<bool, String> {
+    let canceller = cancellations.register(&model_filename);
+    let Some(path) = locate(&window, &downloads, &canceller, &model_filename).await? else {
+        return Ok(false);
+    };
+    worker
+        .run(move |registry| -> Result<bool, String> {
+            load(
+                &window,
+                registry,
+                &path,
+                &model_filename,
+                &architecture,
+                &tokenizer,
+                context_size,
+                use_gpu,
+                None,
+            )?
+            .pinned = true;
+            Event::ModelLoading {
+                message: "Model loaded".to_string(),
+                progress: 1.0,
+            }
+            .send(&window);
+            Ok(true)
+        })
+        .await
+        .map_err(|err| err.to_string())?
+}
+
+/// Unloads the model along with every session running on it
+#[tauri::command]
+async fn unload_model(worker: tauri::State<'_, Worker>, id: String) -> Result<(), String> {
+    worker
+        .run(move |registry| registry.unload(&id))
+        .await
+        .and_then(|result| result)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn list_loaded_models(worker: tauri::State<'_, Worker>) -> Vec<ModelStatus> {
+    worker.status().models
+}
+
+/// Closes the session, unloading its model if it was only loaded for it
+#[tauri::command]
+async fn close_session(worker: tauri::State<'_, Worker>, session: String) -> Result<(), String> {
+    worker
+        .run(move |registry| registry.close(&session))
+        .await
+        .and_then(|result| result)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn get_settings() -> Result<Settings, String> {
+    settings::read().map_err(|err| err.to_string())
+}
+
+/// Saves the settings, applying the memory budget to the next model that's
+/// loaded
+#[tauri::command]
+fn save_settings(worker: tauri::State<'_, Worker>, settings: Settings) -> Result<(), String> {
+    settings::write(&settings).map_err(|err| err.to_string())?;
+    worker
+        .queue(move |registry| registry.budget = settings.memory_budget)
+        .map_err(|err| err.to_string())
+}
+
+#[tauri::command]
+fn list_cache_entries() -> Result<Vec<Entry>, String> {
+    cache::list().map_err(|err| err.to_string())
+}
+
+/// Lists the snapshots kept to pick conversations up again, most recently
+/// used first
+#[tauri::command]
+fn list_snapshots() -> Result<Vec<Entry>, String> {
+    snapshots::list().map_err(|err| err.to_string())
+}
+
+/// Clears the warm-up cache, returning the number of bytes freed
+#[tauri::command]
+fn clear_cache() -> Result<u64, String> {
+    cache::clear().map_err(|err| err.to_string())
+}
+
 fn main() {
     let log_file_path = get_logs_dir().expect("getting log directory");
     create_dir_all(&log_file_path).expect("creating log directory");
@@ -265,6 +1236,11 @@ fn main() {
 
     info!("starting...");
 
+    let settings = settings::read().unwrap_or_else(|err| {
+        warn!(error = err.to_string(), "reading settings, using defaults");
+        Settings::default()
+    });
+
     let builder = tauri::Builder::default()
         .setup(|app| {
             let win = app.get_window("main").unwrap();
@@ -279,13 +1255,53 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             start,
             get_models,
+            get_disk_usage,
+            delete_model,
+            inspect_model,
+            get_model_metadata,
+            save_model_metadata,
             get_architectures,
             get_prompt_templates,
+            create_prompt_template,
+            update_prompt_template,
+            duplicate_prompt_template,
+            delete_prompt_template,
+            import_prompt_templates,
+            export_prompt_templates,
+            get_sampling_presets,
             prompt,
+            undo,
+            regenerate,
+            edit_last_message,
+            count_tokens,
             cancel,
+            list_conversations,
+            open_conversation,
+            rename_conversation,
+            delete_conversation,
+            restore_snapshot,
+            list_siblings,
+            switch_branch,
+            fork,
+            compare_models,
+            load_model,
+            unload_model,
+            list_loaded_models,
+            close_session,
+            get_settings,
+            save_settings,
+            list_cache_entries,
+            list_snapshots,
+            clear_cache,
+            list_downloads,
+            enqueue_download,
+            pause_download,
+            resume_download,
+            remove_download,
         ])
-        .manage(ManagerState(Mutex::new(None)))
-        .manage(Canceller::default());
+        .manage(Worker::spawn(settings.memory_budget))
+        .manage(Cancellations::default())
+        .manage(DownloadManager::default());
 
     // #[cfg(feature = "analytics")]
     // let panic_hook = tauri_plugin_aptabase::Builder::new(env!("APTABASE_KEY"))
@@ -1,16 +1,34 @@
+use crate::cancellation::Canceller;
 use crate::config::get_models_dir;
-use crate::prompt::Template;
+use crate::context_file;
+use crate::conversations::{Conversation, Message};
+use crate::inspect::inspect;
+use crate::overflow::{self, Overflow, OverflowStrategy, RESPONSE_RESERVE, SUMMARY_TOKENS};
+use crate::prompt::{StopSequenceFilter, Template, Variables};
+use crate::sampling::SamplingSettings;
+use crate::sidecar;
+use crate::snapshots;
 use anyhow::Result;
 use futures_util::StreamExt;
 use lazy_static::lazy_static;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::cmp::min;
 use std::convert::Infallible;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
 use std::fs;
-use std::fs::create_dir_all;
+use std::fs::{create_dir_all, OpenOptions};
+use std::io;
 use std::io::Write;
-use std::path::PathBuf;
-use tracing::info;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{info, warn};
 
 lazy_static! {
     pub static ref AVAILABLE_MODELS: Vec<Model> =
@@ -49,6 +67,44 @@ lazy_static! {
     ];
 }
 
+/// Loads the model file. The architecture detected from the file header is
+/// a best guess, so we only use it when nothing valid was selected.
+pub fn load(
+    path: &Path,
+    architecture: &str,
+    tokenizer: &str,
+    params: llm::ModelParameters,
+    progress: impl FnMut(llm::LoadProgress),
+) -> Result<Box<dyn llm::Model>> {
+    let inspection = inspect(path).unwrap_or_default();
+    let selected = AVAILABLE_ARCHITECTURES
+        .iter()
+        .find(|v| v.id == architecture);
+    let detected = inspection
+        .architecture
+        .as_deref()
+        .and_then(|id| AVAILABLE_ARCHITECTURES.iter().find(|v| v.id == id));
+    let architecture = match (detected, selected) {
+        (_, Some(selected)) => selected,
+        (Some(detected), None) => detected,
+        _ => return Err(anyhow::anyhow!("Architecture not found")),
+    };
+    let tokenizer = match tokenizer {
+        "embedded" => llm::TokenizerSource::Embedded,
+        _ => return Err(anyhow::anyhow!("Tokenizer not supported")),
+    };
+    llm::load_dynamic(Some(architecture.inner), path, tokenizer, params, progress).map_err(|e| {
+        match detected {
+            Some(detected) if detected.id != architecture.id => anyhow::anyhow!(
+                "Error loading model: {} (the file looks like a {} model)",
+                e,
+                detected.name
+            ),
+            _ => anyhow::anyhow!("Error loading model: {}", e),
+        }
+    })
+}
+
 /// Returns a list of all .bin files available in the models directory
 /// (with associated metadata if we have them in our models.json file)
 /// and if the model is a model that we don't know about, then we return
@@ -56,19 +112,22 @@ lazy_static! {
 pub async fn get_available_models() -> Result<Vec<Model>> {
     let dir = get_models_dir()?;
     let mut known_models = AVAILABLE_MODELS.clone();
-    let mut models = fs::read_dir(dir)?
+    let mut models = fs::read_dir(&dir)?
         .filter_map(|file| {
             if let Ok(file) = file {
                 if let Some(filename) = file.file_name().to_str() {
-                    if filename.ends_with(".bin")
+                    if is_model_file(filename)
                         && !known_models.iter().any(|m| m.filename.as_str() == filename)
                     {
-                        return Some(Model {
+                        let mut model = Model {
                             name: filename.to_string(),
                             filename: filename.to_string(),
                             custom: true,
                             ..Default::default()
-                        });
+                        };
+                        apply_inspection(&mut model, &file.path());
+                        sidecar::apply(&mut model);
+                        return Some(model);
                     }
                 }
             }
@@ -76,14 +135,106 @@ pub async fn get_available_models() -> Result<Vec<Model>> {
         })
         .collect::<Vec<_>>();
     models.append(&mut known_models);
+    for model in models.iter_mut() {
+        if let Ok(metadata) = fs::metadata(dir.join(&model.filename)) {
+            model.downloaded = true;
+            model.size = Some(metadata.len());
+        }
+    }
     models.sort_by(|a, b| b.custom.cmp(&a.custom));
     Ok(models)
 }
 
+/// Returns true for the GGML `.bin` files that models are stored in.
+fn is_model_file(filename: &str) -> bool {
+    filename.ends_with(".bin")
+}
+
+/// Fills in whatever we can learn about a custom model from its file header.
+fn apply_inspection(model: &mut Model, path: &Path) {
+    match inspect(path) {
+        Ok(inspection) => {
+            model.architecture = inspection.architecture;
+            model.context_length = inspection.context_length;
+            model.vocabulary_size = inspection.vocabulary_size;
+            model.quantization = inspection.quantization;
+        }
+        Err(err) => warn!(error = err.to_string(), "inspecting model"),
+    }
+}
+
+/// Returns the model with the provided filename, whether it's in the
+/// catalog or a custom model in the models directory.
+pub async fn find_model(filename: &str) -> Result<Option<Model>> {
+    Ok(get_available_models()
+        .await?
+        .into_iter()
+        .find(|m| m.filename == filename))
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskUsage {
+    /// Bytes used by fully downloaded models
+    pub models: u64,
+    /// Bytes used by downloads that haven't finished yet
+    pub partial: u64,
+    pub total: u64,
+}
+
+/// Returns the number of bytes used by the models in the models directory,
+/// leaving out anything else that's in there, like sidecar files.
+pub fn get_disk_usage() -> Result<DiskUsage> {
+    let mut usage = DiskUsage::default();
+    for file in fs::read_dir(get_models_dir()?)? {
+        let file = file?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let name = file.file_name().to_string_lossy().to_string();
+        match name.strip_suffix(".part") {
+            Some(model) if is_model_file(model) => usage.partial += metadata.len(),
+            None if is_model_file(&name) => usage.models += metadata.len(),
+            _ => continue,
+        }
+        usage.total += metadata.len();
+    }
+    Ok(usage)
+}
+
+/// Returns the path of the model file with the provided name. Only plain
+/// filenames are allowed so that we never touch anything outside the models
+/// directory.
+pub fn model_path(filename: &str) -> Result<PathBuf> {
+    if Path::new(filename).file_name() != Some(OsStr::new(filename)) {
+        return Err(anyhow::anyhow!("Invalid model filename"));
+    }
+    Ok(get_models_dir()?.join(filename))
+}
+
+/// Deletes the model file, along with any partially downloaded data and
+/// sidecar metadata for it, from the models directory.
+#[tracing::instrument]
+pub fn delete_model(filename: &str) -> Result<()> {
+    let path = model_path(filename)?;
+    let partial = path.with_file_name(format!("{}.part", filename));
+    if !path.exists() && !partial.exists() {
+        return Err(anyhow::anyhow!("Model is not downloaded"));
+    }
+    for path in [path, partial, sidecar::sidecar_path(filename)?] {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    info!("deleted model");
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Model {
-    name: String,
+    pub name: String,
     url: String,
     #[serde(default)]
     pub custom: bool,
@@ -94,9 +245,34 @@ pub struct Model {
     pub quantization: Option<Quantization>,
     pub parameter_count: Option<String>,
     pub labels: Vec<String>,
+    /// The expected SHA-256 digest of the model file, used to verify downloads.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub downloaded: bool,
+    /// The size of the model file on disk in bytes, if it has been downloaded.
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// The id of the model's architecture, if known
+    #[serde(default)]
+    pub architecture: Option<String>,
+    /// The context length the model was trained with, if known
+    #[serde(default)]
+    pub context_length: Option<u64>,
+    #[serde(default)]
+    pub vocabulary_size: Option<usize>,
+    /// The name of the prompt template to use with this model by default
+    #[serde(default)]
+    pub template: Option<String>,
+    /// The context size to start this model with by default
+    #[serde(default)]
+    pub context_size: Option<usize>,
+    /// The sampling settings to use with this model by default
+    #[serde(default)]
+    pub sampling: Option<SamplingSettings>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
 pub enum Quantization {
     #[serde(rename = "none")]
     #[default]
@@ -109,79 +285,509 @@ pub enum Quantization {
     Bit5,
     #[serde(rename = "4-bit")]
     Bit4,
+    #[serde(rename = "3-bit")]
+    Bit3,
     #[serde(rename = "2-bit")]
     Bit2,
 }
 
 #[derive(Serialize, Clone)]
 pub struct Architecture {
-    name: String,
+    pub name: String,
     pub id: String,
     pub inner: llm::ModelArchitecture,
 }
 
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenCount {
+    pub tokens: usize,
+    pub context_size: usize,
+    /// The number of tokens already in the context window
+    pub used: usize,
+    /// What's left of the context window once the text is added
+    pub remaining: usize,
+}
+
+impl TokenCount {
+    /// Counts the text's tokens for a session that already has `used` tokens
+    /// in its context window.
+    pub fn new(model: &dyn llm::Model, used: usize, text: &str) -> Result<Self> {
+        let tokens = overflow::count_tokens(model, text)?;
+        let context_size = model.context_size();
+        Ok(Self {
+            tokens,
+            context_size,
+            used,
+            remaining: context_size.saturating_sub(used + tokens),
+        })
+    }
+}
+
 pub struct ModelManager {
-    pub model: Box<dyn llm::Model>,
+    /// The id of the loaded model the session runs on
+    pub model_id: String,
+    pub model: Arc<dyn llm::Model>,
     pub session: llm::InferenceSession,
     pub template: Template,
+    /// The system prompt the session was warmed up with, including the
+    /// contents of any context files
+    pub system: String,
+    /// The values the template is rendered with
+    pub variables: Variables,
+    /// The sampling settings used when a prompt doesn't provide its own
+    pub sampling: SamplingSettings,
+    /// The maximum number of tokens to generate per response, unless a prompt
+    /// provides its own limit
+    pub maximum_token_count: Option<usize>,
+    /// The seed used for every prompt that doesn't provide its own. When
+    /// neither is set, a random seed is picked for each prompt.
+    pub seed: Option<u64>,
+    /// The conversation that prompts and responses are recorded in
+    pub conversation: Conversation,
+    /// What to do when the conversation no longer fits in the context window
+    pub overflow: OverflowStrategy,
 }
 
 impl ModelManager {
-    pub fn infer<F>(&mut self, prompt: &str, callback: F) -> Result<llm::InferenceStats, String>
+    fn system_prompt(&self) -> String {
+        overflow::system_prompt(&self.system, self.conversation.summary.as_deref())
+    }
+
+    /// Makes room for the prompt and its response if they wouldn't fit in
+    /// the context window, by warming up a new session without the oldest
+    /// messages. Returns what was dropped, if anything.
+    #[tracing::instrument(skip(self, prompt))]
+    pub fn fit(
+        &mut self,
+        prompt: &str,
+        maximum_token_count: Option<usize>,
+    ) -> Result<Option<Overflow>> {
+        let model = self.model.as_ref();
+        let context_size = model.context_size();
+        let reserve = maximum_token_count
+            .or(self.maximum_token_count)
+            .unwrap_or(RESPONSE_RESERVE);
+        let prompt_tokens = overflow::count_tokens(
+            model,
+            &self.template.process(
+                &self.system_prompt(),
+                &self.variables,
+                &self.conversation.messages[self.conversation.dropped..],
+                prompt,
+            )?,
+        )?;
+        if self.session.n_past + prompt_tokens + reserve <= context_size {
+            return Ok(None);
+        }
+
+        let budget = context_size.saturating_sub(prompt_tokens + reserve);
+        let overflow = self.shrink(budget)?.unwrap_or(Overflow {
+            strategy: self.overflow,
+            dropped: 0,
+            summary: self.conversation.summary.clone(),
+        });
+        self.warm_up()?;
+        Ok(Some(overflow))
+    }
+
+    /// Drops the oldest messages that are still in the context window until
+    /// the rest fit in `budget` tokens, summarizing them first if that's the
+    /// strategy. Returns what was dropped, if anything.
+    fn shrink(&mut self, budget: usize) -> Result<Option<Overflow>> {
+        let messages = &self.conversation.messages[self.conversation.dropped..];
+        let dropped = match self.overflow {
+            // The new summary replaces the old one, so it's left out here.
+            OverflowStrategy::Summarize => overflow::fit(
+                self.model.as_ref(),
+                &self.template,
+                &self.system,
+                &self.variables,
+                messages,
+                budget.saturating_sub(SUMMARY_TOKENS),
+            )?,
+            OverflowStrategy::Truncate => overflow::fit(
+                self.model.as_ref(),
+                &self.template,
+                &self.system_prompt(),
+                &self.variables,
+                messages,
+                budget,
+            )?,
+        };
+        if dropped == 0 {
+            return Ok(None);
+        }
+        if self.overflow == OverflowStrategy::Summarize {
+            // Without a summary we're no worse off than truncating.
+            match self.summarize(&messages[..dropped]) {
+                Ok(summary) => self.conversation.summary = Some(summary),
+                Err(err) => warn!(error = err.to_string(), "summarizing dropped messages"),
+            }
+        }
+        self.conversation.dropped += dropped;
+        info!(dropped, "making room in the context window");
+        Ok(Some(Overflow {
+            strategy: self.overflow,
+            dropped,
+            summary: self.conversation.summary.clone(),
+        }))
+    }
+
+    /// Switches the session over to another conversation held with the same
+    /// model, rebuilding the system prompt from its template and context
+    /// files. The session is left as it was if that fails.
+    #[tracing::instrument(skip(self, conversation))]
+    pub fn resume(&mut self, conversation: Conversation) -> Result<Option<Overflow>> {
+        if conversation.model != self.model_id {
+            return Err(anyhow::anyhow!(
+                "The conversation was held with {}, but {} is loaded",
+                conversation.model,
+                self.model_id
+            ));
+        }
+        let context = conversation
+            .context_files
+            .iter()
+            .map(|path| context_file::read(PathBuf::from(path)))
+            .collect::<Result<Vec<_>>>()?
+            .join("\n");
+        let values = [
+            ("date".to_string(), self.variables.date.clone()),
+            ("user_name".to_string(), self.variables.user_name.clone()),
+        ];
+        let variables = Variables::new(
+            &conversation.template,
+            &self.variables.model_name,
+            context,
+            values.into_iter().collect(),
+        );
+        let system = conversation
+            .template
+            .system(&variables, &conversation.messages)?;
+
+        let head = conversation.head;
+        let template = mem::replace(&mut self.template, conversation.template.clone());
+        let conversation = mem::replace(&mut self.conversation, conversation);
+        let system = mem::replace(&mut self.system, system);
+        let variables = mem::replace(&mut self.variables, variables);
+        self.checkout(head).inspect_err(|_| {
+            self.template = template;
+            self.conversation = conversation;
+            self.system = system;
+            self.variables = variables;
+        })
+    }
+
+    /// Moves back to before the last exchange so that it can be regenerated
+    /// or edited. The exchange stays in the conversation as its own branch.
+    /// Returns the user's message from it.
+    #[tracing::instrument(skip(self))]
+    pub fn rewind(&mut self) -> Result<Message> {
+        let head = self
+            .conversation
+            .head
+            .ok_or(anyhow::anyhow!("There is nothing to undo"))?;
+        let exchange = self.conversation.exchanges[head].clone();
+        self.checkout(exchange.parent)?;
+        info!("rewound last exchange");
+        Ok(exchange.prompt)
+    }
+
+    /// Switches the conversation to the provided exchange and brings the
+    /// session in line with it, restoring the snapshot taken after that
+    /// exchange if there is one. Returns what had to be dropped for the
+    /// branch to fit in the context window, if anything.
+    #[tracing::instrument(skip(self))]
+    pub fn checkout(&mut self, head: Option<usize>) -> Result<Option<Overflow>> {
+        self.conversation.checkout(head)?;
+        let restored = head.and_then(|id| {
+            snapshots::restore(
+                &self.conversation.snapshot_id(id),
+                &self.model_id,
+                self.model.as_ref(),
+            )
+            .map_err(|err| warn!(error = err.to_string(), "restoring exchange snapshot"))
+            .ok()
+        });
+        if let Some(session) = restored {
+            self.session = session;
+            return Ok(None);
+        }
+        // Sessions can't be rolled back, so we warm up a new one with as much
+        // of the branch as fits.
+        let budget = self
+            .model
+            .context_size()
+            .saturating_sub(self.maximum_token_count.unwrap_or(RESPONSE_RESERVE));
+        let overflow = self.shrink(budget)?;
+        self.warm_up()?;
+        Ok(overflow)
+    }
+
+    /// Replaces the session with a new one that has been fed the system
+    /// prompt and every message that's still in the context window.
+    fn warm_up(&mut self) -> Result<()> {
+        let warmup = self.template.render(
+            &self.system_prompt(),
+            &self.variables,
+            &self.conversation.messages[self.conversation.dropped..],
+        )?;
+        let tokens = overflow::tokenize(self.model.as_ref(), &warmup, true)?;
+        let mut session = self.model.start_session(Default::default());
+        feed(self.model.as_ref(), &mut session, &tokens)?;
+        self.session = session;
+        Ok(())
+    }
+
+    /// Brings the session back in line with the conversation after the
+    /// response starting at `start` was cut short by a stop sequence, which
+    /// was generated into the session but left out of the response.
+    fn realign(&mut self, start: usize, prompt: &str, response: &str) -> Result<()> {
+        let model = self.model.as_ref();
+        let tokens = overflow::tokenize(model, response, false)?;
+        let generated = self.session.n_past.saturating_sub(start);
+        if let Err(err) = self.session.rewind(model, generated) {
+            // Not every architecture can rewind, so we warm up again instead.
+            info!(error = err.to_string(), "warming up after stop sequence");
+            self.warm_up()?;
+            let prompt = overflow::tokenize(self.model.as_ref(), prompt, false)?;
+            feed(self.model.as_ref(), &mut self.session, &prompt)?;
+        }
+        feed(self.model.as_ref(), &mut self.session, &tokens)
+    }
+
+    /// Asks the model to summarize the provided messages in a separate session.
+    fn summarize(&self, messages: &[Message]) -> Result<String> {
+        let request = overflow::summary_request(self.conversation.summary.as_deref(), messages);
+        let prompt = self.template.process("", &self.variables, &[], &request)?;
+        let tokens = overflow::tokenize(self.model.as_ref(), &prompt, true)?;
+        let mut filter = StopSequenceFilter::new(&self.template.stop);
+        let mut summary = String::new();
+        self.model
+            .start_session(Default::default())
+            .infer(
+                self.model.as_ref(),
+                &mut rand::thread_rng(),
+                &llm::InferenceRequest {
+                    prompt: (&tokens).into(),
+                    parameters: &self.sampling.parameters(),
+                    play_back_previous_tokens: false,
+                    maximum_token_count: Some(SUMMARY_TOKENS),
+                },
+                &mut Default::default(),
+                |res| {
+                    if let llm::InferenceResponse::InferredToken(token) = res {
+                        summary.push_str(&filter.push(&token));
+                    }
+                    Ok::<_, Infallible>(if filter.is_stopped() {
+                        llm::InferenceFeedback::Halt
+                    } else {
+                        llm::InferenceFeedback::Continue
+                    })
+                },
+            )
+            .map_err(|err| anyhow::anyhow!("Error summarizing: {}", err))?;
+        summary.push_str(&filter.finish());
+        Ok(summary.trim().to_string())
+    }
+
+    pub fn infer<F>(
+        &mut self,
+        prompt: &str,
+        sampling: Option<&SamplingSettings>,
+        maximum_token_count: Option<usize>,
+        seed: u64,
+        mut callback: F,
+    ) -> Result<llm::InferenceStats, String>
     where
         F: FnMut(llm::InferenceResponse) -> Result<llm::InferenceFeedback, Infallible>,
     {
-        self.session
+        let parameters = sampling.unwrap_or(&self.sampling).parameters();
+        let prompt = self
+            .template
+            .process(
+                &self.system_prompt(),
+                &self.variables,
+                &self.conversation.messages[self.conversation.dropped..],
+                prompt,
+            )
+            .map_err(|err| err.to_string())?;
+        let tokens = overflow::tokenize(self.model.as_ref(), &prompt, self.session.n_past == 0)
+            .map_err(|err| err.to_string())?;
+        let start = self.session.n_past + tokens.len();
+        let mut filter = StopSequenceFilter::new(&self.template.stop);
+        let mut response = String::new();
+        let stats = self
+            .session
             .infer(
                 self.model.as_ref(),
-                &mut rand::thread_rng(),
+                &mut StdRng::seed_from_u64(seed),
                 &llm::InferenceRequest {
-                    prompt: self.template.process(prompt).as_str().into(),
-                    parameters: &llm::InferenceParameters::default(),
+                    prompt: (&tokens).into(),
+                    parameters: &parameters,
                     play_back_previous_tokens: false,
-                    maximum_token_count: None,
+                    maximum_token_count: maximum_token_count.or(self.maximum_token_count),
                 },
                 &mut Default::default(),
-                callback,
+                |res| match res {
+                    llm::InferenceResponse::InferredToken(token) => {
+                        let text = filter.push(&token);
+                        let feedback = if text.is_empty() {
+                            Ok(llm::InferenceFeedback::Continue)
+                        } else {
+                            response.push_str(&text);
+                            callback(llm::InferenceResponse::InferredToken(text))
+                        };
+                        if filter.is_stopped() {
+                            Ok(llm::InferenceFeedback::Halt)
+                        } else {
+                            feedback
+                        }
+                    }
+                    res => callback(res),
+                },
             )
-            .map_err(|e| format!("Error inferring: {}", e))
+            .map_err(|e| format!("Error inferring: {}", e))?;
+
+        // Anything still held back turned out not to be a stop sequence.
+        let stopped = filter.is_stopped();
+        let rest = filter.finish();
+        if stopped {
+            self.realign(start, &prompt, &response)
+                .map_err(|err| err.to_string())?;
+        }
+        if !rest.is_empty() {
+            let _ = callback(llm::InferenceResponse::InferredToken(rest));
+        }
+        Ok(stats)
     }
 }
 
-#[tracing::instrument(skip(progress))]
+/// Feeds the tokens into the session without generating anything.
+fn feed(
+    model: &dyn llm::Model,
+    session: &mut llm::InferenceSession,
+    tokens: &[llm::TokenId],
+) -> Result<()> {
+    session
+        .feed_prompt(
+            model,
+            tokens,
+            &mut Default::default(),
+            llm::feed_prompt_callback(|_| Ok::<_, Infallible>(llm::InferenceFeedback::Continue)),
+        )
+        .map_err(|err| anyhow::anyhow!("Error feeding prompt: {}", err))
+}
+
+/// Returned when a model download is halted by the [`Canceller`]. The partial
+/// file is kept on disk so that the download can be resumed later.
+#[derive(Debug)]
+pub struct DownloadCancelled;
+
+impl Display for DownloadCancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Download cancelled")
+    }
+}
+
+impl std::error::Error for DownloadCancelled {}
+
+#[tracing::instrument(skip(canceller, progress))]
 async fn download_file<F>(
     url: &str,
     destination: &PathBuf,
     filename: &str,
+    sha256: Option<&str>,
+    canceller: &Canceller,
     progress: F,
 ) -> Result<PathBuf>
 where
     F: Fn(u64, u64, f32),
 {
     create_dir_all(destination)?;
+    let partial = destination.join(format!("{}.part", filename));
     let destination = destination.join(filename);
-    let destination = destination.to_str().unwrap();
-    info!("downloading model to {}", destination);
-    let response = reqwest::get(url).await?;
-    let total_size = response.content_length().unwrap_or(0);
-    let mut stream = response.bytes_stream();
-    let mut file = std::fs::File::create(destination)?;
-    let mut downloaded: u64 = 0;
-    while let Some(item) = stream.next().await {
-        let chunk = item?;
-        file.write_all(&chunk)?;
-        let new_downloaded = min(downloaded + chunk.len() as u64, total_size);
-        if total_size > 0 {
-            let p = new_downloaded as f32 / total_size as f32;
-            progress(downloaded, total_size, p);
-        }
-        downloaded = new_downloaded;
-    }
-    Ok(PathBuf::from(destination))
-}
-
-#[tracing::instrument(skip(progress))]
-pub async fn get_local_model<F>(filename: &str, progress: F) -> Result<PathBuf>
+    info!("downloading model to {}", destination.display());
+
+    // If a previous download was interrupted, we ask the server for the remaining
+    // bytes only and append them to the partial file.
+    let mut downloaded = fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+    let mut request = reqwest::Client::new().get(url);
+    if downloaded > 0 {
+        info!(offset = downloaded, "resuming partial download");
+        request = request.header(RANGE, format!("bytes={}-", downloaded));
+    }
+    let response = request.send().await?;
+
+    // The server tells us there is nothing left to download, which means the
+    // partial file is already complete and only needs to be verified.
+    if response.status() != StatusCode::RANGE_NOT_SATISFIABLE {
+        let response = response.error_for_status()?;
+        let mut file = if response.status() == StatusCode::PARTIAL_CONTENT {
+            OpenOptions::new().append(true).open(&partial)?
+        } else {
+            // The server ignored our range request, so we have to start over.
+            downloaded = 0;
+            fs::File::create(&partial)?
+        };
+        let total_size = downloaded + response.content_length().unwrap_or(0);
+        let mut stream = response.bytes_stream();
+        while let Some(item) = stream.next().await {
+            if canceller.is_cancelled() {
+                file.flush()?;
+                info!(downloaded, "download cancelled");
+                return Err(DownloadCancelled.into());
+            }
+            let chunk = item?;
+            file.write_all(&chunk)?;
+            let new_downloaded = min(downloaded + chunk.len() as u64, total_size);
+            if total_size > 0 {
+                let p = new_downloaded as f32 / total_size as f32;
+                progress(downloaded, total_size, p);
+            }
+            downloaded = new_downloaded;
+        }
+        file.flush()?;
+    }
+
+    // Catalog entries without a digest yet are trusted as they are.
+    match sha256 {
+        Some(expected) => {
+            let actual = file_sha256(&partial)?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                // A corrupt partial file can't be resumed, so we throw it away.
+                fs::remove_file(&partial)?;
+                return Err(anyhow::anyhow!(
+                    "Checksum mismatch for {}: expected {}, got {}",
+                    filename,
+                    expected,
+                    actual
+                ));
+            }
+            info!(sha256 = actual, "verified model checksum");
+        }
+        None => warn!("no checksum available, skipping verification"),
+    }
+
+    fs::rename(&partial, &destination)?;
+    Ok(destination)
+}
+
+/// Returns the hex-encoded SHA-256 digest of the file at the provided path.
+pub fn file_sha256(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[tracing::instrument(skip(canceller, progress))]
+pub async fn get_local_model<F>(
+    filename: &str,
+    canceller: &Canceller,
+    progress: F,
+) -> Result<PathBuf>
 where
     F: Fn(u64, u64, f32),
 {
@@ -191,7 +797,15 @@ where
             .iter()
             .find(|m| m.filename == filename)
             .ok_or(anyhow::anyhow!("Model not found"))?;
-        download_file(&model.url, &models_dir, &model.filename, progress).await?;
+        download_file(
+            &model.url,
+            &models_dir,
+            &model.filename,
+            model.sha256.as_deref(),
+            canceller,
+            progress,
+        )
+        .await?;
         info!(filename = model.filename, "finished downloading model");
     }
     Ok(models_dir.join(filename))
@@ -199,7 +813,8 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::models::AVAILABLE_MODELS;
+    use crate::models::{file_sha256, model_path, AVAILABLE_MODELS};
+    use crate::prompt::AVAILABLE_TEMPLATES;
 
     #[test]
     fn test_model_existence() {
@@ -215,4 +830,63 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_model_checksums() {
+        // Hugging Face reports the SHA-256 of files stored with Git LFS in the
+        // etag of the redirect to the actual download.
+        let client = reqwest::blocking::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        for model in AVAILABLE_MODELS.iter() {
+            let Some(expected) = model.sha256.as_deref() else {
+                continue;
+            };
+            let response = client.head(&model.url).send().expect("Failed to get model");
+            let actual = response
+                .headers()
+                .get("x-linked-etag")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim_matches('"').to_string());
+            assert_eq!(
+                actual.as_deref(),
+                Some(expected),
+                "Checksum mismatch for {}",
+                model.filename
+            );
+        }
+    }
+
+    #[test]
+    fn test_model_templates() {
+        for model in AVAILABLE_MODELS.iter() {
+            let template = model.template.as_deref();
+            assert!(
+                AVAILABLE_TEMPLATES
+                    .iter()
+                    .any(|t| Some(t.name.as_str()) == template),
+                "{} has no known template",
+                model.filename
+            );
+        }
+    }
+
+    #[test]
+    fn test_model_path() {
+        assert!(model_path("../settings.json").is_err());
+        assert!(model_path("/etc/passwd").is_err());
+        assert!(model_path("").is_err());
+    }
+
+    #[test]
+    fn test_file_sha256() {
+        let path = std::env::temp_dir().join("chitchat-test-sha256.txt");
+        std::fs::write(&path, "hello world").unwrap();
+        assert_eq!(
+            file_sha256(&path).unwrap(),
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,159 @@
+use crate::conversations::{Message, Role};
+use crate::prompt::{Template, Variables, BOS, EOS};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// How many tokens are kept free for the response when no limit was set.
+pub const RESPONSE_RESERVE: usize = 512;
+
+/// The most tokens a summary of older turns may take up.
+pub const SUMMARY_TOKENS: usize = 256;
+
+/// What to do with older turns once a conversation no longer fits in the
+/// context window. The system prompt is always kept.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowStrategy {
+    /// Drop the oldest turns
+    #[default]
+    Truncate,
+    /// Replace the oldest turns with a summary written by the model
+    Summarize,
+}
+
+/// Describes what was taken out of the context window.
+#[derive(Clone, Debug)]
+pub struct Overflow {
+    pub strategy: OverflowStrategy,
+    /// The number of messages that are no longer in the context window
+    pub dropped: usize,
+    /// The summary that replaced the dropped messages, if any
+    pub summary: Option<String>,
+}
+
+pub fn count_tokens(model: &dyn llm::Model, text: &str) -> Result<usize> {
+    Ok(tokenize(model, text, false)?.len())
+}
+
+/// Tokenizes a rendered prompt, replacing the [`BOS`] and [`EOS`] markers
+/// with the model's own tokens. `beginning` should be set when the prompt is
+/// fed into a new session, which has to start with a beginning of sequence
+/// token.
+pub fn tokenize(model: &dyn llm::Model, text: &str, beginning: bool) -> Result<Vec<llm::TokenId>> {
+    let mut tokens = vec![];
+    let mut bos = beginning;
+    let mut rest = text;
+    loop {
+        let end = rest.find([BOS, EOS]).unwrap_or(rest.len());
+        if bos {
+            tokens.extend(model.bot_token_id());
+        }
+        tokens.extend(
+            model
+                .tokenizer()
+                .tokenize(&rest[..end], false)?
+                .into_iter()
+                .map(|(_, id)| id),
+        );
+        let Some(marker) = rest[end..].chars().next() else {
+            return Ok(tokens);
+        };
+        bos = marker == BOS;
+        if marker == EOS {
+            tokens.push(model.eot_token_id());
+        }
+        rest = &rest[end + marker.len_utf8()..];
+    }
+}
+
+/// The system prompt followed by the summary of dropped messages, if any.
+pub fn system_prompt(system: &str, summary: Option<&str>) -> String {
+    match summary {
+        Some(summary) => format!(
+            "{}\n\nSummary of the conversation so far: {}",
+            system, summary
+        ),
+        None => system.to_string(),
+    }
+}
+
+/// Returns how many of the oldest messages have to go so that the system
+/// prompt and the remaining messages fit in `budget` tokens.
+pub fn fit(
+    model: &dyn llm::Model,
+    template: &Template,
+    system: &str,
+    variables: &Variables,
+    messages: &[Message],
+    budget: usize,
+) -> Result<usize> {
+    let budget = budget.saturating_sub(count_tokens(
+        model,
+        &template.render(system, variables, &[])?,
+    )?);
+    let counts = messages
+        .iter()
+        .map(|m| {
+            count_tokens(
+                model,
+                &template.render("", variables, std::slice::from_ref(m))?,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(first_kept(messages, &counts, budget))
+}
+
+/// Returns the index of the first message to keep so that the token counts
+/// of the kept messages add up to no more than `budget`. Whole exchanges are
+/// dropped so that what's left never starts with an assistant turn.
+fn first_kept(messages: &[Message], counts: &[usize], budget: usize) -> usize {
+    let mut total: usize = counts.iter().sum();
+    for (i, message) in messages.iter().enumerate() {
+        if total <= budget && message.role == Role::User {
+            return i;
+        }
+        total -= counts[i];
+    }
+    messages.len()
+}
+
+/// Builds the prompt that asks the model to summarize the provided messages,
+/// folding in the summary of anything that was dropped before them.
+pub fn summary_request(previous: Option<&str>, messages: &[Message]) -> String {
+    let mut request = "Summarize the following conversation in a few sentences, keeping any \
+                       names, facts and decisions.\n\n"
+        .to_string();
+    if let Some(previous) = previous {
+        request.push_str(&format!("Earlier summary: {}\n", previous));
+    }
+    for message in messages {
+        let role = match message.role {
+            Role::User => "User",
+            Role::Assistant => "Assistant",
+        };
+        request.push_str(&format!("{}: {}\n", role, message.content));
+    }
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conversations::{Message, Role};
+    use crate::overflow::first_kept;
+    use crate::test_util::message;
+
+    fn messages(roles: &[Role]) -> Vec<Message> {
+        roles.iter().map(|&role| message(role, "")).collect()
+    }
+
+    #[test]
+    fn test_first_kept() {
+        let messages = messages(&[Role::User, Role::Assistant, Role::User, Role::Assistant]);
+        let counts = [10, 40, 10, 40];
+        assert_eq!(first_kept(&messages, &counts, 100), 0);
+        assert_eq!(first_kept(&messages, &counts, 90), 2);
+        // Dropping just the first user message would leave an assistant turn first
+        assert_eq!(first_kept(&messages, &counts, 95), 2);
+        assert_eq!(first_kept(&messages, &counts, 10), 4);
+    }
+}
@@ -1,24 +1,651 @@
+use crate::conversations::{Message, Role};
+use anyhow::{anyhow, Result};
 use lazy_static::lazy_static;
+use minijinja::{Environment, UndefinedBehavior};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 lazy_static! {
     pub static ref AVAILABLE_TEMPLATES: Vec<Template> =
         serde_json::from_str(include_str!("../data/prompt-templates.json")).unwrap();
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Variables every template can use on top of the ones it defines itself.
+/// `prompt` is only available to `template`.
+const BUILTIN_VARIABLES: [&str; 6] = [
+    "prompt",
+    "date",
+    "user_name",
+    "context",
+    "model_name",
+    "messages",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Template {
     #[serde(default)]
     pub name: String,
+    /// The system prompt the session is warmed up with
     pub warmup: String,
+    /// Wraps user prompts for templates without `roles`
+    #[serde(default)]
     pub template: String,
+    /// Generation stops as soon as the model produces any of these strings
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// How each role's turns are written out. Templates without roles fall
+    /// back to the `warmup` and `template` strings.
+    #[serde(default)]
+    pub roles: Option<Roles>,
+    /// Whether the template was created by the user rather than built in
+    #[serde(default)]
+    pub custom: bool,
+    /// User-defined variables and their default values
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+/// The values `warmup` and `template` are rendered with.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Variables {
+    pub date: String,
+    pub user_name: String,
+    /// The contents of every context file
+    pub context: String,
+    pub model_name: String,
+    #[serde(flatten)]
+    pub custom: BTreeMap<String, String>,
+}
+
+impl Variables {
+    /// Collects the values for the provided template. The provided values take
+    /// precedence over the template's defaults and may also replace `date` and
+    /// `user_name`.
+    pub fn new(
+        template: &Template,
+        model_name: &str,
+        context: String,
+        mut values: BTreeMap<String, String>,
+    ) -> Self {
+        let date = values
+            .remove("date")
+            .unwrap_or_else(|| chrono::Local::now().format("%Y-%m-%d").to_string());
+        let user_name = values
+            .remove("user_name")
+            .or_else(|| std::env::var("USER").ok())
+            .or_else(|| std::env::var("USERNAME").ok())
+            .unwrap_or_default();
+        let mut custom = template.variables.clone();
+        custom.extend(values);
+        custom.retain(|name, _| !BUILTIN_VARIABLES.contains(&name.as_str()));
+        Self {
+            date,
+            user_name,
+            context,
+            model_name: model_name.to_string(),
+            custom,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Context<'a> {
+    #[serde(flatten)]
+    variables: &'a Variables,
+    messages: &'a [Message],
+    // Left out entirely when rendering `warmup` so that using it there fails
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt: Option<&'a str>,
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    // Unknown variables are errors rather than silently rendering as nothing
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.set_keep_trailing_newline(true);
+    env
+}
+
+/// Stands in for the model's beginning of sequence token in rendered
+/// prompts until they are tokenized with [`crate::overflow::tokenize`].
+pub const BOS: char = '\u{2}';
+
+/// Stands in for the model's end of sequence token in rendered prompts.
+pub const EOS: char = '\u{3}';
+
+/// Removes anything that would be mistaken for a special token.
+fn clean(text: &str) -> String {
+    text.replace([BOS, EOS], "")
+}
+
+/// Templates used to write the tokens out as text, which the tokenizer
+/// doesn't turn back into the special tokens. A non-empty string is read as
+/// `true` so that those templates still load.
+fn token<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Token {
+        Flag(bool),
+        Text(String),
+    }
+    Ok(match Token::deserialize(deserializer)? {
+        Token::Flag(flag) => flag,
+        Token::Text(text) => !text.is_empty(),
+    })
+}
+
+/// The text written before and after a single turn.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct Wrapper {
+    pub prefix: String,
+    pub suffix: String,
+}
+
+impl Wrapper {
+    fn wrap(&self, content: &str) -> String {
+        format!("{}{}{}", self.prefix, content, self.suffix)
+    }
+
+    fn render(&self, env: &Environment, context: &Context) -> Result<Self> {
+        Ok(Self {
+            prefix: env.render_str(&self.prefix, context)?,
+            suffix: env.render_str(&self.suffix, context)?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Roles {
+    pub system: Wrapper,
+    pub user: Wrapper,
+    pub assistant: Wrapper,
+    /// Written between two consecutive turns
+    pub separator: String,
+    /// Whether every exchange except the first one starts with the model's
+    /// beginning of sequence token. The first one always does.
+    #[serde(deserialize_with = "token")]
+    pub bos: bool,
+    /// Whether every assistant turn ends with the model's end of sequence
+    /// token.
+    #[serde(deserialize_with = "token")]
+    pub eos: bool,
+    /// Puts the system prompt inside the first user turn instead of in a turn
+    /// of its own, as LLaMA 2 Chat expects.
+    pub merge_system: bool,
+}
+
+impl Roles {
+    /// Every string that is rendered as a template.
+    fn sources(&self) -> [&String; 7] {
+        [
+            &self.system.prefix,
+            &self.system.suffix,
+            &self.user.prefix,
+            &self.user.suffix,
+            &self.assistant.prefix,
+            &self.assistant.suffix,
+            &self.separator,
+        ]
+    }
+
+    /// Renders the wrappers and separator with the provided variables, so
+    /// that they can use them just like `warmup` and `template`.
+    fn render(&self, variables: &Variables, messages: &[Message]) -> Result<Self> {
+        let env = environment();
+        let context = Context {
+            variables,
+            messages,
+            prompt: None,
+        };
+        Ok(Self {
+            system: self.system.render(&env, &context)?,
+            user: self.user.render(&env, &context)?,
+            assistant: self.assistant.render(&env, &context)?,
+            separator: env.render_str(&self.separator, &context)?,
+            ..self.clone()
+        })
+    }
 }
 
 impl Template {
-    pub fn process(&self, prompt: &str) -> String {
-        self.template
-            .replace("{{prompt}}", prompt)
-            .trim()
-            .to_string()
+    /// Makes sure that `warmup` and `template` parse, only use variables we
+    /// know about, and that `template` includes the prompt. Templates with
+    /// `roles` never render `template`, so their wrappers are checked
+    /// instead.
+    pub fn check(&self) -> Result<()> {
+        if let Some(name) = self
+            .variables
+            .keys()
+            .find(|name| BUILTIN_VARIABLES.contains(&name.as_str()))
+        {
+            return Err(anyhow!(
+                "Variable {} is built in and can't be redefined",
+                name
+            ));
+        }
+        let env = environment();
+        let mut sources = vec![(&self.warmup, false)];
+        match &self.roles {
+            Some(roles) => sources.extend(roles.sources().map(|source| (source, false))),
+            None => sources.push((&self.template, true)),
+        }
+        for (source, prompt) in sources {
+            let known = |name: &str| {
+                self.variables.contains_key(name)
+                    || (BUILTIN_VARIABLES.contains(&name) && (prompt || name != "prompt"))
+            };
+            let declared = env.template_from_str(source)?.undeclared_variables(false);
+            if let Some(name) = declared.iter().find(|name| !known(name)) {
+                return Err(anyhow!("Unknown template variable {}", name));
+            }
+            if prompt && !declared.contains("prompt") {
+                return Err(anyhow!(
+                    "Template {} is missing the {{{{prompt}}}} placeholder",
+                    self.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders `warmup` into the system prompt. The context goes in front of
+    /// it unless the template places `{{context}}` itself.
+    pub fn system(&self, variables: &Variables, messages: &[Message]) -> Result<String> {
+        let env = environment();
+        let template = env.template_from_str(&self.warmup)?;
+        let system = template.render(Context {
+            variables,
+            messages,
+            prompt: None,
+        })?;
+        if variables.context.is_empty() || template.undeclared_variables(false).contains("context")
+        {
+            Ok(system)
+        } else {
+            Ok(format!("{}\n{}", variables.context, system))
+        }
+    }
+
+    /// Renders the system prompt followed by every message of a conversation,
+    /// leaving the last assistant turn open so that it can be continued with
+    /// [`Template::process`].
+    pub fn render(
+        &self,
+        system: &str,
+        variables: &Variables,
+        messages: &[Message],
+    ) -> Result<String> {
+        let Some(roles) = &self.roles else {
+            let mut parts = vec![system.to_string()];
+            for (i, message) in messages.iter().enumerate() {
+                parts.push(match message.role {
+                    Role::User => self.process("", variables, &messages[..i], &message.content)?,
+                    Role::Assistant => message.content.clone(),
+                });
+            }
+            parts.retain(|s| !s.is_empty());
+            return Ok(parts.join("\n"));
+        };
+        let roles = roles.render(variables, messages)?;
+        let mut rendered = String::new();
+        let mut previous = None;
+        if !system.is_empty() {
+            rendered.push_str(&roles.system.wrap(&clean(system)));
+            previous = Some(Turn::System);
+        }
+        for message in messages {
+            let turn = Turn::from(message.role);
+            rendered.push_str(&roles.open(previous, turn));
+            rendered.push_str(&clean(&message.content));
+            if turn == Turn::User {
+                rendered.push_str(&roles.user.suffix);
+            }
+            previous = Some(turn);
+        }
+        Ok(rendered)
+    }
+
+    /// Returns the text that continues a conversation rendered with
+    /// [`Template::render`] with a new user prompt, up to the point where the
+    /// assistant is expected to answer.
+    pub fn process(
+        &self,
+        system: &str,
+        variables: &Variables,
+        messages: &[Message],
+        prompt: &str,
+    ) -> Result<String> {
+        let Some(roles) = &self.roles else {
+            let rendered = environment().render_str(
+                &self.template,
+                Context {
+                    variables,
+                    messages,
+                    prompt: Some(prompt),
+                },
+            )?;
+            return Ok(rendered.trim().to_string());
+        };
+        let roles = roles.render(variables, messages)?;
+        let previous = match messages.last() {
+            Some(message) => Some(Turn::from(message.role)),
+            None if !system.is_empty() => Some(Turn::System),
+            None => None,
+        };
+        Ok(format!(
+            "{}{}{}{}",
+            roles.open(previous, Turn::User),
+            clean(prompt),
+            roles.user.suffix,
+            roles.open(Some(Turn::User), Turn::Assistant)
+        ))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Turn {
+    System,
+    User,
+    Assistant,
+}
+
+impl From<Role> for Turn {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::User => Turn::User,
+            Role::Assistant => Turn::Assistant,
+        }
+    }
+}
+
+impl Roles {
+    /// Returns everything written between the content of the previous turn
+    /// and the content of the next one.
+    fn open(&self, previous: Option<Turn>, next: Turn) -> String {
+        let mut text = String::new();
+        if previous == Some(Turn::Assistant) {
+            text.push_str(&self.assistant.suffix);
+            if self.eos {
+                text.push(EOS);
+            }
+        }
+        if previous.is_some() {
+            text.push_str(&self.separator);
+        }
+        match next {
+            Turn::System => text.push_str(&self.system.prefix),
+            Turn::User => {
+                if previous == Some(Turn::Assistant) && self.bos {
+                    text.push(BOS);
+                }
+                if !(self.merge_system && previous == Some(Turn::System)) {
+                    text.push_str(&self.user.prefix);
+                }
+            }
+            Turn::Assistant => text.push_str(&self.assistant.prefix),
+        }
+        text
+    }
+}
+
+/// Holds back streamed text that could be the beginning of a stop sequence
+/// until we know whether it actually is one, so that stop sequences never
+/// make it to the interface.
+pub struct StopSequenceFilter<'a> {
+    stop: &'a [String],
+    pending: String,
+    stopped: bool,
+}
+
+impl<'a> StopSequenceFilter<'a> {
+    pub fn new(stop: &'a [String]) -> Self {
+        Self {
+            stop,
+            pending: String::new(),
+            stopped: false,
+        }
+    }
+
+    /// Adds newly generated text and returns the text that is safe to show.
+    pub fn push(&mut self, text: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.pending.push_str(text);
+        let found = self
+            .stop
+            .iter()
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| self.pending.find(s.as_str()))
+            .min();
+        if let Some(index) = found {
+            self.stopped = true;
+            self.pending.truncate(index);
+            return std::mem::take(&mut self.pending);
+        }
+        let held = self
+            .stop
+            .iter()
+            .map(|s| partial_match(&self.pending, s))
+            .max()
+            .unwrap_or(0);
+        let rest = self.pending.split_off(self.pending.len() - held);
+        std::mem::replace(&mut self.pending, rest)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Returns whatever text was still being held back.
+    pub fn finish(self) -> String {
+        self.pending
+    }
+}
+
+/// Returns the length of the longest suffix of `text` that is a proper
+/// prefix of `stop`.
+fn partial_match(text: &str, stop: &str) -> usize {
+    stop.char_indices()
+        .map(|(i, _)| i)
+        .skip(1)
+        .filter(|&i| text.ends_with(&stop[..i]))
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conversations::Role;
+    use crate::prompt::{Roles, StopSequenceFilter, Variables, AVAILABLE_TEMPLATES};
+    use crate::test_util::message;
+
+    fn template(name: &str) -> &'static crate::prompt::Template {
+        AVAILABLE_TEMPLATES.iter().find(|t| t.name == name).unwrap()
+    }
+
+    #[test]
+    fn test_render_chatml() {
+        let template = template("ChatML");
+        let vars = Variables::default();
+        let mut messages = vec![
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello!"),
+        ];
+        let rendered = template.render("Be nice.", &vars, &messages).unwrap();
+        assert_eq!(
+            rendered,
+            "<|im_start|>system\nBe nice.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\nHello!"
+        );
+        let next = template
+            .process("Be nice.", &vars, &messages, "Bye")
+            .unwrap();
+        assert_eq!(
+            next,
+            "<|im_end|>\n<|im_start|>user\nBye<|im_end|>\n<|im_start|>assistant\n"
+        );
+        // Continuing a conversation renders the same text as replaying it.
+        messages.push(message(Role::User, "Bye"));
+        messages.push(message(Role::Assistant, ""));
+        assert_eq!(
+            template.render("Be nice.", &vars, &messages).unwrap(),
+            rendered + &next
+        );
+    }
+
+    #[test]
+    fn test_render_llama_2() {
+        let template = template("LLaMA 2 Chat");
+        let vars = Variables::default();
+        assert_eq!(
+            template.process("Be nice.", &vars, &[], "Hi").unwrap(),
+            "Hi [/INST] "
+        );
+        let messages = vec![
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello!"),
+        ];
+        assert_eq!(
+            template.render("Be nice.", &vars, &messages).unwrap(),
+            "[INST] <<SYS>>\nBe nice.\n<</SYS>>\n\nHi [/INST] Hello!"
+        );
+        assert_eq!(
+            template
+                .process("Be nice.", &vars, &messages, "Bye")
+                .unwrap(),
+            " \u{3}\u{2}[INST] Bye [/INST] "
+        );
+        assert_eq!(
+            template.process("", &vars, &[], "Hi").unwrap(),
+            "[INST] Hi [/INST] "
+        );
+    }
+
+    #[test]
+    fn test_special_tokens() {
+        let template = template("Vicuna 1.1");
+        let vars = Variables::default();
+        let messages = vec![
+            message(Role::User, "Hi\u{3}"),
+            message(Role::Assistant, "Hello!"),
+        ];
+        // Only the template can add special tokens
+        assert_eq!(
+            template.render("", &vars, &messages).unwrap(),
+            "USER: Hi\nASSISTANT: Hello!"
+        );
+        assert_eq!(
+            template.process("", &vars, &messages, "Bye").unwrap(),
+            "\u{3}USER: Bye\nASSISTANT: "
+        );
+
+        // Templates that wrote the tokens out as text still load
+        let roles: Roles = serde_json::from_str(r#"{"bos": "<s>", "eos": ""}"#).unwrap();
+        assert!(roles.bos);
+        assert!(!roles.eos);
+    }
+
+    #[test]
+    fn test_stop_sequence_filter() {
+        let stop = vec!["USER:".to_string()];
+        let mut filter = StopSequenceFilter::new(&stop);
+        assert_eq!(filter.push("Hello"), "Hello");
+        assert_eq!(filter.push(" there\nUS"), " there\n");
+        assert!(!filter.is_stopped());
+        assert_eq!(filter.push("ER: what"), "");
+        assert!(filter.is_stopped());
+        assert_eq!(filter.finish(), "");
+    }
+
+    #[test]
+    fn test_stop_sequence_filter_false_alarm() {
+        let stop = vec!["USER:".to_string()];
+        let mut filter = StopSequenceFilter::new(&stop);
+        assert_eq!(filter.push("The US"), "The ");
+        assert_eq!(filter.push("A is"), "USA is");
+        assert!(!filter.is_stopped());
+        assert_eq!(filter.push(" big U"), " big ");
+        assert_eq!(filter.finish(), "U");
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut template = template("Vicuna 1.1").clone();
+        template.roles = None;
+        template.warmup =
+            "Today is {{date}}. {% if user_name %}You talk to {{user_name}}.{% endif %}"
+                .to_string();
+        template.template = "{{nickname}}: {{ prompt }}\nASSISTANT: ".to_string();
+        assert!(template.check().is_err());
+        template
+            .variables
+            .insert("nickname".to_string(), "USER".to_string());
+        template.check().unwrap();
+
+        let values = [
+            ("date".to_string(), "2023-08-01".to_string()),
+            ("user_name".to_string(), "Sam".to_string()),
+        ];
+        let vars = Variables::new(&template, "Vicuna", "Some notes".to_string(), values.into());
+        let system = template.system(&vars, &[]).unwrap();
+        assert_eq!(system, "Some notes\nToday is 2023-08-01. You talk to Sam.");
+        assert_eq!(
+            template.process(&system, &vars, &[], "Hi").unwrap(),
+            "USER: Hi\nASSISTANT:"
+        );
+
+        template.warmup = "{{prompt}}".to_string();
+        assert!(template.check().is_err());
+    }
+
+    #[test]
+    fn test_role_variables() {
+        let mut template = template("Vicuna 1.1").clone();
+        let roles = template.roles.as_mut().unwrap();
+        roles.user.prefix = "{{nickname}}: ".to_string();
+        roles.assistant.prefix = "{{model_name | upper}}: ".to_string();
+        assert!(template.check().is_err());
+        template
+            .variables
+            .insert("nickname".to_string(), "SAM".to_string());
+        template.check().unwrap();
+
+        let vars = Variables::new(&template, "Vicuna", String::new(), Default::default());
+        let messages = vec![
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello!"),
+        ];
+        assert_eq!(
+            template.render("Be nice.", &vars, &messages).unwrap(),
+            "Be nice.\nSAM: Hi\nVICUNA: Hello!"
+        );
+        assert_eq!(
+            template
+                .process("Be nice.", &vars, &messages, "Bye")
+                .unwrap(),
+            "\u{3}SAM: Bye\nVICUNA: "
+        );
+
+        // The prompt only ever goes in between the wrappers
+        template.roles.as_mut().unwrap().user.suffix = "{{prompt}}".to_string();
+        assert!(template.check().is_err());
+    }
+
+    #[test]
+    fn test_check_roles() {
+        // Templates with roles don't need a `template` at all
+        let mut template = template("ChatML").clone();
+        template.template = String::new();
+        template.check().unwrap();
+
+        template.roles = None;
+        assert!(template.check().is_err());
+        template.template = "{{prompt}}".to_string();
+        template.check().unwrap();
     }
 }
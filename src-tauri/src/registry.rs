@@ -0,0 +1,385 @@
+use anyhow::{anyhow, Result};
+use bytesize::ByteSize;
+use chitchat_lib::models::ModelManager;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::info;
+
+/// The session that commands apply to when they don't name one.
+pub const DEFAULT_SESSION: &str = "default";
+
+/// What the registry needs to know about a session running on a model.
+pub trait Session {
+    fn model_id(&self) -> &str;
+}
+
+impl Session for ModelManager {
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+}
+
+/// A model that has been loaded into memory. Every session running on it
+/// shares the same weights.
+pub struct LoadedModel<M = Arc<dyn llm::Model>> {
+    /// The model's filename, which doubles as its id
+    pub id: String,
+    pub model: M,
+    pub context_size: usize,
+    pub use_gpu: bool,
+    /// The estimated memory the model's weights take up, in bytes
+    pub memory: u64,
+    /// The estimated memory each session running on the model takes up on
+    /// top of that, in bytes
+    pub session_memory: u64,
+    /// Models that were loaded explicitly stay loaded until they're unloaded,
+    /// rather than going away with their last session.
+    pub pinned: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelStatus {
+    pub id: String,
+    pub context_size: usize,
+    pub use_gpu: bool,
+    /// The estimated memory the model and its sessions take up, in bytes
+    pub memory: u64,
+    pub pinned: bool,
+    /// The ids of the sessions running on the model
+    pub sessions: Vec<String>,
+}
+
+/// Keeps track of the loaded models and the sessions running on them, and
+/// refuses to load anything that would go over the memory budget.
+pub struct Registry<M = Arc<dyn llm::Model>, S = ModelManager> {
+    models: BTreeMap<String, LoadedModel<M>>,
+    sessions: BTreeMap<String, S>,
+    /// The most memory loaded models and their sessions may take up
+    /// together, if limited
+    pub budget: Option<u64>,
+}
+
+impl<M, S> Default for Registry<M, S> {
+    fn default() -> Self {
+        Self {
+            models: Default::default(),
+            sessions: Default::default(),
+            budget: None,
+        }
+    }
+}
+
+impl<M: Clone, S: Session> Registry<M, S> {
+    pub fn new(budget: Option<u64>) -> Self {
+        Self {
+            budget,
+            ..Default::default()
+        }
+    }
+
+    /// The estimated memory taken up by every loaded model and session, in
+    /// bytes.
+    pub fn used(&self) -> u64 {
+        self.models.values().map(|m| self.memory(m)).sum()
+    }
+
+    /// The estimated memory taken up by a model and its sessions.
+    fn memory(&self, model: &LoadedModel<M>) -> u64 {
+        let sessions = self.sessions_of(&model.id).count() as u64;
+        model.memory + sessions * model.session_memory
+    }
+
+    fn sessions_of<'a>(&'a self, id: &'a str) -> impl Iterator<Item = (&'a String, &'a S)> {
+        self.sessions
+            .iter()
+            .filter(move |(_, s)| s.model_id() == id)
+    }
+
+    /// Fails if taking up `memory` more bytes would go over the budget. When
+    /// a session is about to be replaced by one running on the model `id`,
+    /// whatever closing it would free up counts as available.
+    pub fn reserve(&self, memory: u64, replacing: Option<&str>, id: &str) -> Result<()> {
+        let freed = replacing.map_or(0, |session| self.freed(session, id));
+        let used = self.used().saturating_sub(freed);
+        match self.budget {
+            Some(budget) if used + memory > budget => Err(anyhow!(
+                "Loading the model needs {} of memory, but only {} of the {} budget is left",
+                ByteSize(memory),
+                ByteSize(budget.saturating_sub(used)),
+                ByteSize(budget)
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The most sessions on the provided models that fit in the budget at
+    /// once, up to `most`, counting the largest ones. Fails if not even one
+    /// of them fits.
+    pub fn fitting(&self, ids: &[&str], most: usize) -> Result<usize> {
+        let mut memory = ids
+            .iter()
+            .map(|id| {
+                self.models
+                    .get(*id)
+                    .map(|m| m.session_memory)
+                    .ok_or(anyhow!("{} is not loaded", id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        memory.sort_unstable_by(|a, b| b.cmp(a));
+        let Some(budget) = self.budget else {
+            return Ok(most);
+        };
+        if let (Some(largest), Some(id)) = (memory.first(), ids.first()) {
+            self.reserve(*largest, None, id)?;
+        }
+        let free = budget.saturating_sub(self.used());
+        let mut total = 0;
+        let fitting = memory
+            .iter()
+            .take(most)
+            .take_while(|m| {
+                total += *m;
+                total <= free
+            })
+            .count();
+        Ok(fitting.max(1))
+    }
+
+    /// The memory that closing the session would free up, keeping the model
+    /// `keep` loaded.
+    fn freed(&self, session: &str, keep: &str) -> u64 {
+        let Some(model) = self
+            .sessions
+            .get(session)
+            .and_then(|s| self.models.get(s.model_id()))
+        else {
+            return 0;
+        };
+        let unloaded =
+            model.id != keep && !model.pinned && self.sessions_of(&model.id).count() == 1;
+        model.session_memory + if unloaded { model.memory } else { 0 }
+    }
+
+    /// Returns the loaded model with the provided id if it was loaded with
+    /// the same settings.
+    pub fn find(
+        &mut self,
+        id: &str,
+        context_size: usize,
+        use_gpu: bool,
+    ) -> Option<&mut LoadedModel<M>> {
+        self.models
+            .get_mut(id)
+            .filter(|m| m.context_size == context_size && m.use_gpu == use_gpu)
+    }
+
+    /// Returns true unless the model is loaded and has to stay that way,
+    /// because it's pinned or another session than `session` runs on it.
+    pub fn replaceable(&self, id: &str, session: Option<&str>) -> bool {
+        self.models.get(id).is_none_or(|model| {
+            !model.pinned
+                && self
+                    .sessions_of(id)
+                    .all(|(other, _)| Some(other.as_str()) == session)
+        })
+    }
+
+    pub fn is_loaded(&self, id: &str) -> bool {
+        self.models.contains_key(id)
+    }
+
+    pub fn model(&self, id: &str) -> Result<M> {
+        self.models
+            .get(id)
+            .map(|m| m.model.clone())
+            .ok_or(anyhow!("{} is not loaded", id))
+    }
+
+    pub fn insert(&mut self, model: LoadedModel<M>) -> Result<()> {
+        if self.models.contains_key(&model.id) {
+            return Err(anyhow!(
+                "{} is already loaded with different settings, unload it first",
+                model.id
+            ));
+        }
+        info!(id = model.id, memory = model.memory, "loaded model");
+        self.models.insert(model.id.clone(), model);
+        Ok(())
+    }
+
+    /// Unloads the model along with every session running on it.
+    pub fn unload(&mut self, id: &str) -> Result<()> {
+        self.models.remove(id).ok_or(anyhow!("Model not loaded"))?;
+        self.sessions.retain(|_, session| session.model_id() != id);
+        info!(id, "unloaded model");
+        Ok(())
+    }
+
+    pub fn session(&mut self, id: &str) -> Result<&mut S> {
+        self.sessions
+            .get_mut(id)
+            .ok_or(anyhow!("Model not started"))
+    }
+
+    pub fn sessions(&self) -> impl Iterator<Item = (&String, &S)> {
+        self.sessions.iter()
+    }
+
+    pub fn sessions_mut(&mut self) -> impl Iterator<Item = &mut S> {
+        self.sessions.values_mut()
+    }
+
+    /// Adds the session, replacing any other session with the same id.
+    pub fn open(&mut self, id: &str, session: S) {
+        if let Some(previous) = self.sessions.insert(id.to_string(), session) {
+            self.release(previous.model_id());
+        }
+    }
+
+    /// Closes the session, unloading its model if nothing else needs it.
+    pub fn close(&mut self, id: &str) -> Result<()> {
+        let session = self
+            .sessions
+            .remove(id)
+            .ok_or(anyhow!("Session not found"))?;
+        self.release(session.model_id());
+        Ok(())
+    }
+
+    /// Unloads a model that wasn't loaded explicitly once its last session
+    /// is gone.
+    pub fn release(&mut self, id: &str) {
+        let unused = self.sessions_of(id).next().is_none();
+        if unused && self.models.get(id).is_some_and(|m| !m.pinned) {
+            self.models.remove(id);
+            info!(id, "unloaded unused model");
+        }
+    }
+
+    pub fn list(&self) -> Vec<ModelStatus> {
+        self.models
+            .values()
+            .map(|m| ModelStatus {
+                id: m.id.clone(),
+                context_size: m.context_size,
+                use_gpu: m.use_gpu,
+                memory: self.memory(m),
+                pinned: m.pinned,
+                sessions: self.sessions_of(&m.id).map(|(id, _)| id.clone()).collect(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::registry::{LoadedModel, Registry, Session};
+
+    const GB: u64 = 1_000_000_000;
+
+    struct TestSession(&'static str);
+
+    impl Session for TestSession {
+        fn model_id(&self) -> &str {
+            self.0
+        }
+    }
+
+    fn model(id: &str, pinned: bool) -> LoadedModel<()> {
+        LoadedModel {
+            id: id.to_string(),
+            model: (),
+            context_size: 2048,
+            use_gpu: false,
+            memory: 4 * GB,
+            session_memory: GB,
+            pinned,
+        }
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut registry = Registry::<(), TestSession>::new(Some(12 * GB));
+        registry.insert(model("a", false)).unwrap();
+        registry.open("default", TestSession("a"));
+        assert_eq!(registry.used(), 5 * GB);
+
+        // Another model and its session fit, but not a third one
+        registry.reserve(5 * GB, None, "b").unwrap();
+        registry.insert(model("b", false)).unwrap();
+        registry.open("writing", TestSession("b"));
+        assert!(registry.reserve(5 * GB, None, "c").is_err());
+
+        // Replacing a session counts the memory it would free up, including
+        // its model's if nothing else needs it
+        registry.reserve(5 * GB, Some("writing"), "c").unwrap();
+        // but not the model's if the new session runs on it as well
+        assert!(registry.reserve(5 * GB, Some("writing"), "b").is_err());
+        registry.reserve(GB, Some("writing"), "b").unwrap();
+        // and nothing for sessions that don't exist
+        assert!(registry.reserve(5 * GB, Some("coding"), "c").is_err());
+    }
+
+    #[test]
+    fn test_release() {
+        let mut registry = Registry::<(), TestSession>::new(None);
+        registry.insert(model("a", false)).unwrap();
+        registry.open("default", TestSession("a"));
+        registry.open("writing", TestSession("a"));
+        assert_eq!(registry.list()[0].memory, 6 * GB);
+
+        assert!(!registry.replaceable("a", Some("default")));
+        registry.close("default").unwrap();
+        assert!(registry.is_loaded("a"));
+        assert!(registry.replaceable("a", Some("writing")));
+        assert!(registry.replaceable("c", None));
+        // Replacing the last session on a model unloads it
+        registry.insert(model("b", false)).unwrap();
+        registry.open("writing", TestSession("b"));
+        assert!(!registry.is_loaded("a"));
+        registry.close("writing").unwrap();
+        assert!(!registry.is_loaded("b"));
+        assert_eq!(registry.used(), 0);
+    }
+
+    #[test]
+    fn test_pinned() {
+        let mut registry = Registry::<(), TestSession>::new(None);
+        registry.insert(model("a", true)).unwrap();
+        registry.open("default", TestSession("a"));
+        assert!(!registry.replaceable("a", Some("default")));
+        registry.close("default").unwrap();
+        assert!(registry.is_loaded("a"));
+        assert_eq!(registry.used(), 4 * GB);
+
+        // Closing the only session of a pinned model doesn't free its weights
+        registry.open("default", TestSession("a"));
+        registry.budget = Some(6 * GB);
+        assert!(registry.reserve(3 * GB, Some("default"), "b").is_err());
+        registry.reserve(2 * GB, Some("default"), "b").unwrap();
+
+        registry.unload("a").unwrap();
+        assert!(registry.list().is_empty());
+        assert!(registry.session("default").is_err());
+    }
+
+    #[test]
+    fn test_fitting() {
+        let mut registry = Registry::<(), TestSession>::new(None);
+        registry.insert(model("a", false)).unwrap();
+        registry.insert(model("b", false)).unwrap();
+        registry.insert(model("c", false)).unwrap();
+        assert_eq!(registry.fitting(&["a", "b", "c"], 2).unwrap(), 2);
+        assert!(registry.fitting(&["a", "d"], 2).is_err());
+
+        // Only as many sessions as fit run at once, but at least one
+        registry.budget = Some(14 * GB);
+        assert_eq!(registry.fitting(&["a", "b", "c"], 4).unwrap(), 2);
+        registry.budget = Some(13 * GB);
+        assert_eq!(registry.fitting(&["a", "b", "c"], 4).unwrap(), 1);
+        registry.budget = Some(12 * GB);
+        assert!(registry.fitting(&["a", "b", "c"], 4).is_err());
+    }
+}
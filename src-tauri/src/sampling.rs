@@ -0,0 +1,165 @@
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+lazy_static! {
+    pub static ref AVAILABLE_PRESETS: Vec<Preset> =
+        serde_json::from_str(include_str!("../data/sampling-presets.json")).unwrap();
+}
+
+/// Controls how the next token is picked from the model's predictions.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SamplingSettings {
+    pub temperature: f32,
+    /// Only sample from the k most likely tokens
+    pub top_k: usize,
+    /// Only sample from the most likely tokens whose probabilities add up to p
+    pub top_p: f32,
+    pub repeat_penalty: f32,
+    /// The number of most recent tokens the repeat penalty applies to
+    pub repetition_window: usize,
+}
+
+impl Default for SamplingSettings {
+    /// Uses the same defaults as llm's own sampler.
+    fn default() -> Self {
+        let sampler = llm::samplers::TopPTopK::default();
+        Self {
+            temperature: sampler.temperature,
+            top_k: sampler.top_k,
+            top_p: sampler.top_p,
+            repeat_penalty: sampler.repeat_penalty,
+            repetition_window: sampler.repetition_penalty_last_n,
+        }
+    }
+}
+
+impl SamplingSettings {
+    /// Fails if any of the settings is out of range for the sampler.
+    pub fn validate(&self) -> Result<()> {
+        if self.temperature < 0.0 {
+            return Err(anyhow!("Temperature can't be negative"));
+        }
+        if self.top_k == 0 {
+            return Err(anyhow!("Top K must be at least 1"));
+        }
+        if !(0.0..=1.0).contains(&self.top_p) {
+            return Err(anyhow!("Top P must be between 0 and 1"));
+        }
+        if self.repetition_window == 0 {
+            return Err(anyhow!("The repetition window must be at least 1 token"));
+        }
+        Ok(())
+    }
+
+    fn sampler(&self) -> llm::samplers::TopPTopK {
+        llm::samplers::TopPTopK {
+            top_k: self.top_k,
+            top_p: self.top_p,
+            repeat_penalty: self.repeat_penalty,
+            temperature: self.temperature,
+            repetition_penalty_last_n: self.repetition_window,
+            ..Default::default()
+        }
+    }
+
+    pub fn parameters(&self) -> llm::InferenceParameters {
+        llm::InferenceParameters {
+            sampler: Arc::new(self.sampler()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Preset {
+    pub name: String,
+    pub settings: SamplingSettings,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sampling::{SamplingSettings, AVAILABLE_PRESETS};
+
+    #[test]
+    fn test_default_matches_llm() {
+        let default = llm::samplers::TopPTopK::default();
+        let sampler = SamplingSettings::default().sampler();
+        assert_eq!(sampler.top_k, default.top_k);
+        assert_eq!(sampler.top_p, default.top_p);
+        assert_eq!(sampler.repeat_penalty, default.repeat_penalty);
+        assert_eq!(sampler.temperature, default.temperature);
+        assert_eq!(
+            sampler.repetition_penalty_last_n,
+            default.repetition_penalty_last_n
+        );
+    }
+
+    #[test]
+    fn test_sampler() {
+        let settings = SamplingSettings {
+            temperature: 0.2,
+            top_k: 20,
+            top_p: 0.8,
+            repeat_penalty: 1.15,
+            repetition_window: 128,
+        };
+        let sampler = settings.sampler();
+        assert_eq!(sampler.temperature, 0.2);
+        assert_eq!(sampler.top_k, 20);
+        assert_eq!(sampler.top_p, 0.8);
+        assert_eq!(sampler.repeat_penalty, 1.15);
+        assert_eq!(sampler.repetition_penalty_last_n, 128);
+    }
+
+    #[test]
+    fn test_presets() {
+        let balanced = AVAILABLE_PRESETS
+            .iter()
+            .find(|p| p.name == "Balanced")
+            .unwrap();
+        assert_eq!(balanced.settings, SamplingSettings::default());
+        for preset in AVAILABLE_PRESETS.iter() {
+            preset.settings.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        SamplingSettings::default().validate().unwrap();
+        let invalid = [
+            SamplingSettings {
+                temperature: -0.1,
+                ..Default::default()
+            },
+            SamplingSettings {
+                top_k: 0,
+                ..Default::default()
+            },
+            SamplingSettings {
+                top_p: 1.1,
+                ..Default::default()
+            },
+            SamplingSettings {
+                top_p: -0.1,
+                ..Default::default()
+            },
+            SamplingSettings {
+                repetition_window: 0,
+                ..Default::default()
+            },
+        ];
+        for settings in invalid {
+            assert!(settings.validate().is_err());
+        }
+        // Greedy sampling is fine
+        SamplingSettings {
+            temperature: 0.0,
+            top_k: 1,
+            ..Default::default()
+        }
+        .validate()
+        .unwrap();
+    }
+}
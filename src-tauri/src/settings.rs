@@ -0,0 +1,39 @@
+use crate::config::get_settings_file;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// Settings that apply to the whole application rather than to a single
+/// model or conversation, stored in `~/.chitchat/settings.json`.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Settings {
+    /// The most memory, in bytes, that loaded models may take up together.
+    /// There is no limit when this isn't set.
+    #[serde(default)]
+    pub memory_budget: Option<u64>,
+    /// The most disk space, in bytes, that warmed up sessions may take up.
+    /// The least recently used ones are removed to stay under it.
+    #[serde(default)]
+    pub cache_limit: Option<u64>,
+    /// The most disk space, in bytes, that conversation snapshots may take
+    /// up. The least recently used ones are removed to stay under it.
+    #[serde(default)]
+    pub snapshot_limit: Option<u64>,
+}
+
+pub fn read() -> Result<Settings> {
+    let path = get_settings_file()?;
+    if !path.exists() {
+        return Ok(Settings::default());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+pub fn write(settings: &Settings) -> Result<()> {
+    fs::write(
+        get_settings_file()?,
+        serde_json::to_string_pretty(settings)?,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,187 @@
+use crate::models::{model_path, Model, AVAILABLE_ARCHITECTURES};
+use crate::sampling::SamplingSettings;
+use crate::templates;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// User-provided metadata for a custom model, stored next to the model file
+/// as `<model filename>.json`, e.g. `llama.bin.json`. Anything set here takes
+/// precedence over what we were able to detect from the model file itself.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Sidecar {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub architecture: Option<String>,
+    /// The name of the prompt template to use by default
+    pub template: Option<String>,
+    pub context_size: Option<usize>,
+    pub sampling: Option<SamplingSettings>,
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+impl Sidecar {
+    pub fn apply(self, model: &mut Model) {
+        if let Some(name) = self.name {
+            model.name = name;
+        }
+        if let Some(description) = self.description {
+            model.description = description;
+        }
+        if self.architecture.is_some() {
+            model.architecture = self.architecture;
+        }
+        if self.template.is_some() {
+            model.template = self.template;
+        }
+        if self.context_size.is_some() {
+            model.context_size = self.context_size;
+        }
+        if self.sampling.is_some() {
+            model.sampling = self.sampling;
+        }
+        if !self.labels.is_empty() {
+            model.labels = self.labels;
+        }
+    }
+}
+
+/// Returns the path of the sidecar file for the provided model filename,
+/// which is the filename with `.json` appended.
+pub fn sidecar_path(filename: &str) -> Result<PathBuf> {
+    Ok(model_path(filename)?.with_file_name(format!("{}.json", filename)))
+}
+
+/// Reads the sidecar for the provided model, returning `None` if the model
+/// doesn't have one.
+pub fn read(filename: &str) -> Result<Option<Sidecar>> {
+    read_from(&sidecar_path(filename)?)
+}
+
+fn read_from(path: &Path) -> Result<Option<Sidecar>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+}
+
+/// Merges the sidecar into the model if there is one. A broken sidecar
+/// shouldn't hide the model, so errors are only logged.
+pub fn apply(model: &mut Model) {
+    match read(&model.filename) {
+        Ok(Some(sidecar)) => sidecar.apply(model),
+        Ok(None) => {}
+        Err(err) => warn!(
+            error = err.to_string(),
+            filename = model.filename,
+            "reading model sidecar"
+        ),
+    }
+}
+
+#[tracing::instrument(skip(sidecar))]
+pub fn write(filename: &str, sidecar: &Sidecar) -> Result<()> {
+    write_to(&sidecar_path(filename)?, sidecar)?;
+    info!("saved model sidecar");
+    Ok(())
+}
+
+fn write_to(path: &Path, sidecar: &Sidecar) -> Result<()> {
+    if let Some(architecture) = &sidecar.architecture {
+        if !AVAILABLE_ARCHITECTURES
+            .iter()
+            .any(|a| &a.id == architecture)
+        {
+            return Err(anyhow!("Unknown architecture {}", architecture));
+        }
+    }
+    if let Some(template) = &sidecar.template {
+        if templates::find(template)?.is_none() {
+            return Err(anyhow!("Unknown prompt template {}", template));
+        }
+    }
+    if let Some(sampling) = &sidecar.sampling {
+        sampling.validate()?;
+    }
+    fs::write(path, serde_json::to_string_pretty(sidecar)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::Model;
+    use crate::sidecar::{read_from, sidecar_path, write_to, Sidecar};
+    use crate::templates::FALLBACK_TEMPLATE;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_sidecar_path() {
+        let path = sidecar_path("llama.bin").unwrap();
+        assert!(path.ends_with("llama.bin.json"));
+        assert!(sidecar_path("../llama.bin").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = env::temp_dir().join(format!("chitchat-sidecar-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("llama.bin.json");
+        assert!(read_from(&path).unwrap().is_none());
+
+        let sidecar = Sidecar {
+            name: Some("My LLaMA".to_string()),
+            architecture: Some("llama".to_string()),
+            context_size: Some(4096),
+            labels: vec!["chat".to_string()],
+            ..Default::default()
+        };
+        write_to(&path, &sidecar).unwrap();
+        let read = read_from(&path).unwrap().unwrap();
+
+        let mut model = Model::default();
+        model.name = "llama".to_string();
+        model.description = "A custom model".to_string();
+        model.template = Some("Alpaca".to_string());
+        read.apply(&mut model);
+        assert_eq!(model.name, "My LLaMA");
+        // Fields the sidecar doesn't set are left alone
+        assert_eq!(model.description, "A custom model");
+        assert_eq!(model.template.as_deref(), Some("Alpaca"));
+        assert_eq!(model.architecture.as_deref(), Some("llama"));
+        assert_eq!(model.context_size, Some(4096));
+        assert_eq!(model.labels, vec!["chat".to_string()]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_architecture() {
+        let path = env::temp_dir().join("chitchat-sidecar-unknown.json");
+        let sidecar = Sidecar {
+            architecture: Some("unknown".to_string()),
+            ..Default::default()
+        };
+        assert!(write_to(&path, &sidecar).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_unknown_template() {
+        let path = env::temp_dir().join("chitchat-sidecar-template.json");
+        let mut sidecar = Sidecar {
+            template: Some("Unknown".to_string()),
+            ..Default::default()
+        };
+        assert!(write_to(&path, &sidecar).is_err());
+        assert!(!path.exists());
+
+        sidecar.template = Some(FALLBACK_TEMPLATE.to_string());
+        write_to(&path, &sidecar).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+use crate::config::get_snapshots_dir;
+use crate::settings;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// How much disk space conversation snapshots may take up unless the
+/// settings say otherwise.
+const DEFAULT_LIMIT: u64 = 8 * 1024 * 1024 * 1024;
+
+/// Written in front of the session state so that we can tell which model a
+/// snapshot belongs to without deserializing the whole thing.
+#[derive(Serialize, Deserialize)]
+pub struct Header {
+    pub model: String,
+}
+
+/// A snapshot stored on disk.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    pub id: String,
+    /// The filename of the model the snapshot was taken with
+    pub model: String,
+    /// Size of the snapshot on disk in bytes
+    pub size: u64,
+    /// Seconds since the unix epoch at which the snapshot was last saved or
+    /// restored
+    pub used_at: u64,
+}
+
+/// Serializes the session's state (KV cache and token history) to disk so
+/// that the conversation can be picked up again without warming up. The
+/// least recently used snapshots are removed to stay under the limit.
+#[tracing::instrument(skip(session))]
+pub fn save(id: &str, model: &str, session: &mut llm::InferenceSession) -> Result<()> {
+    write(&path(id)?, model, session)?;
+    info!("saved session snapshot");
+    let limit = settings::read()
+        .unwrap_or_default()
+        .snapshot_limit
+        .unwrap_or(DEFAULT_LIMIT);
+    evict(&get_snapshots_dir()?, limit, id)?;
+    Ok(())
+}
+
+/// Restores the session that was saved for the provided id. The snapshot
+/// must have been taken with the same model, and the model must have been
+/// loaded with the same context size.
+#[tracing::instrument(skip(model))]
+pub fn restore(id: &str, filename: &str, model: &dyn llm::Model) -> Result<llm::InferenceSession> {
+    let session = read(&path(id)?, filename, model)?;
+    info!("restored session snapshot");
+    Ok(session)
+}
+
+pub fn exists(id: &str) -> bool {
+    path(id).map(|p| p.exists()).unwrap_or(false)
+}
+
+/// Returns every conversation snapshot, most recently used first.
+pub fn list() -> Result<Vec<Entry>> {
+    entries(&get_snapshots_dir()?)
+}
+
+/// Removes the snapshot with the provided id along with the snapshots of
+/// every exchange of the conversation with that id.
+pub fn delete(id: &str) -> Result<()> {
+    let path = path(id)?;
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let prefix = format!("{}-", id);
+    for file in fs::read_dir(get_snapshots_dir()?)? {
+        let file = file?;
+        if file
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".bin"))
+        {
+            fs::remove_file(file.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a snapshot of the session, taken with the provided model, to the
+/// provided path.
+pub fn write(path: &Path, model: &str, session: &mut llm::InferenceSession) -> Result<()> {
+    let temporary = path.with_extension("bin.tmp");
+    {
+        let mut encoder = zstd::Encoder::new(BufWriter::new(File::create(&temporary)?), 1)?;
+        bincode::serialize_into(
+            &mut encoder,
+            &Header {
+                model: model.to_string(),
+            },
+        )?;
+        // SAFETY: the session is borrowed mutably for as long as the snapshot
+        // reference is alive, so nothing else can touch its memory meanwhile.
+        let snapshot = unsafe { session.get_snapshot() };
+        bincode::serialize_into(&mut encoder, &snapshot)?;
+        encoder.finish()?;
+    }
+    fs::rename(temporary, path)?;
+    Ok(())
+}
+
+/// Lists the snapshots in the provided directory, most recently used first.
+pub fn entries(dir: &Path) -> Result<Vec<Entry>> {
+    let mut entries = vec![];
+    for file in fs::read_dir(dir)? {
+        let file = file?;
+        let path = file.path();
+        let Some(id) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(".bin"))
+        else {
+            continue;
+        };
+        let metadata = file.metadata()?;
+        match read_header(&path) {
+            Ok(header) => entries.push(Entry {
+                id: id.to_string(),
+                model: header.model,
+                size: metadata.len(),
+                used_at: metadata
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            }),
+            Err(err) => warn!(error = err.to_string(), id, "reading snapshot"),
+        }
+    }
+    entries.sort_by_key(|e| Reverse(e.used_at));
+    Ok(entries)
+}
+
+/// Removes the least recently used snapshots in the provided directory
+/// until they fit within the limit. The snapshot that was just saved is
+/// always kept.
+pub fn evict(dir: &Path, limit: u64, keep: &str) -> Result<()> {
+    let mut entries = vec![];
+    for file in fs::read_dir(dir)? {
+        let file = file?;
+        let path = file.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("bin") {
+            continue;
+        }
+        let metadata = file.metadata()?;
+        let kept = path.file_stem().and_then(|s| s.to_str()) == Some(keep);
+        entries.push((path, metadata.len(), metadata.modified()?, kept));
+    }
+    // The kept snapshot first, then the most recently used ones
+    entries.sort_by_key(|(_, _, modified, kept)| (!kept, Reverse(*modified)));
+    let mut total = 0;
+    for (path, size, _, kept) in entries {
+        total += size;
+        if total > limit && !kept {
+            fs::remove_file(&path)?;
+            total -= size;
+            info!(
+                path = path.to_str().unwrap_or_default(),
+                "evicted session snapshot"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Reads just the header of the snapshot at the provided path.
+pub fn read_header(path: &Path) -> Result<Header> {
+    let mut decoder = zstd::Decoder::new(File::open(path)?)?;
+    Ok(bincode::deserialize_from(&mut decoder)?)
+}
+
+/// Reads the snapshot at the provided path back into a session for the
+/// provided model, as long as it was taken with that model.
+pub fn read(path: &Path, filename: &str, model: &dyn llm::Model) -> Result<llm::InferenceSession> {
+    if !path.exists() {
+        return Err(anyhow!("No snapshot found"));
+    }
+    let mut decoder = zstd::Decoder::new(File::open(path)?)?;
+    let header: Header = bincode::deserialize_from(&mut decoder)?;
+    if header.model != filename {
+        return Err(anyhow!(
+            "Snapshot was taken with {}, not {}",
+            header.model,
+            filename
+        ));
+    }
+    let snapshot: llm::InferenceSnapshot = bincode::deserialize_from(&mut decoder)?;
+    let session = llm::InferenceSession::from_snapshot(snapshot, model)?;
+    // The modification time doubles as the last use, for eviction.
+    File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())?;
+    Ok(session)
+}
+
+fn path(id: &str) -> Result<PathBuf> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(anyhow!("Invalid snapshot id"));
+    }
+    Ok(get_snapshots_dir()?.join(format!("{}.bin", id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::snapshots::evict;
+    use crate::test_util::temp_dir;
+    use std::fs;
+    use std::fs::File;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    fn snapshot(dir: &Path, id: &str, size: usize, age: u64) {
+        let path = dir.join(format!("{}.bin", id));
+        fs::write(&path, vec![0; size]).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_evict() {
+        let dir = temp_dir("snapshots-evict");
+        snapshot(&dir, "oldest", 100, 300);
+        snapshot(&dir, "older", 100, 200);
+        snapshot(&dir, "newest", 100, 100);
+        snapshot(&dir, "saved", 100, 400);
+        fs::write(dir.join("digests.json"), "{}").unwrap();
+
+        evict(&dir, 250, "saved").unwrap();
+        assert!(dir.join("saved.bin").exists());
+        assert!(dir.join("newest.bin").exists());
+        assert!(!dir.join("older.bin").exists());
+        assert!(!dir.join("oldest.bin").exists());
+        assert!(dir.join("digests.json").exists());
+
+        // A snapshot bigger than the limit is kept if it was just saved
+        evict(&dir, 50, "saved").unwrap();
+        assert!(dir.join("saved.bin").exists());
+        assert!(!dir.join("newest.bin").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
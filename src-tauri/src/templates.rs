@@ -0,0 +1,243 @@
+use crate::config::get_templates_file;
+use crate::prompt::{Template, AVAILABLE_TEMPLATES};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Returns the built-in templates followed by the user's own.
+pub fn list() -> Result<Vec<Template>> {
+    let mut templates = AVAILABLE_TEMPLATES.clone();
+    templates.extend(read()?);
+    Ok(templates)
+}
+
+pub fn find(name: &str) -> Result<Option<Template>> {
+    Ok(list()?.into_iter().find(|t| t.name == name))
+}
+
+/// The template used for models that don't name one we know about. It
+/// feeds prompts in as they are, since we can't tell what chat format, if
+/// any, the model was trained on.
+pub const FALLBACK_TEMPLATE: &str = "Plain Completion";
+
+/// Returns the template with the provided name, or the plain completion
+/// template if a model doesn't name one we know about.
+pub fn default_for(name: Option<&str>) -> Result<Template> {
+    if let Some(name) = name {
+        match find(name)? {
+            Some(template) => return Ok(template),
+            None => warn!(name, "model refers to an unknown prompt template"),
+        }
+    }
+    AVAILABLE_TEMPLATES
+        .iter()
+        .find(|t| t.name == FALLBACK_TEMPLATE)
+        .cloned()
+        .ok_or(anyhow!("Template {} not found", FALLBACK_TEMPLATE))
+}
+
+fn read() -> Result<Vec<Template>> {
+    let path = get_templates_file()?;
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut templates: Vec<Template> = serde_json::from_str(&fs::read_to_string(path)?)?;
+    for template in templates.iter_mut() {
+        template.custom = true;
+    }
+    Ok(templates)
+}
+
+fn write(templates: &[Template]) -> Result<()> {
+    // Write to a temporary file first so that a crash mid-write can't lose
+    // every template.
+    let path = get_templates_file()?;
+    let temporary = path.with_extension("json.tmp");
+    fs::write(&temporary, serde_json::to_string_pretty(templates)?)?;
+    fs::rename(temporary, path)?;
+    Ok(())
+}
+
+/// Checks that the template renders and that its name isn't taken by any of
+/// the other templates.
+fn validate(template: &Template, others: &[Template]) -> Result<()> {
+    if template.name.trim().is_empty() {
+        return Err(anyhow!("Template name can't be empty"));
+    }
+    template.check()?;
+    if others.iter().any(|t| t.name == template.name) {
+        return Err(anyhow!("A template named {} already exists", template.name));
+    }
+    Ok(())
+}
+
+/// Returns the provided name, or the first "(copy)" variant of it that
+/// isn't taken yet.
+fn unique_name(name: &str, templates: &[Template]) -> String {
+    let taken = |candidate: &str| templates.iter().any(|t| t.name == candidate);
+    if !taken(name) {
+        return name.to_string();
+    }
+    let mut candidate = format!("{} (copy)", name);
+    let mut n = 2;
+    while taken(&candidate) {
+        candidate = format!("{} (copy {})", name, n);
+        n += 1;
+    }
+    candidate
+}
+
+#[tracing::instrument(skip(template), fields(name = template.name))]
+pub fn create(mut template: Template) -> Result<Template> {
+    template.custom = true;
+    validate(&template, &list()?)?;
+    let mut templates = read()?;
+    templates.push(template.clone());
+    write(&templates)?;
+    info!("created prompt template");
+    Ok(template)
+}
+
+/// Replaces the user template with the provided name, which may rename it.
+#[tracing::instrument(skip(template))]
+pub fn update(name: &str, mut template: Template) -> Result<Template> {
+    template.custom = true;
+    let mut templates = read()?;
+    let index = templates
+        .iter()
+        .position(|t| t.name == name)
+        .ok_or(anyhow!(
+            "Built-in templates can't be changed, duplicate it instead"
+        ))?;
+    let others = AVAILABLE_TEMPLATES
+        .iter()
+        .chain(templates.iter().filter(|t| t.name != name))
+        .cloned()
+        .collect::<Vec<_>>();
+    validate(&template, &others)?;
+    templates[index] = template.clone();
+    write(&templates)?;
+    info!("updated prompt template");
+    Ok(template)
+}
+
+/// Copies any template, including built-in ones, into a new user template.
+#[tracing::instrument]
+pub fn duplicate(name: &str) -> Result<Template> {
+    let all = list()?;
+    let mut template = all
+        .iter()
+        .find(|t| t.name == name)
+        .cloned()
+        .ok_or(anyhow!("Template {} not found", name))?;
+    template.name = unique_name(name, &all);
+    create(template)
+}
+
+#[tracing::instrument]
+pub fn delete(name: &str) -> Result<()> {
+    let mut templates = read()?;
+    let len = templates.len();
+    templates.retain(|t| t.name != name);
+    if templates.len() == len {
+        return Err(anyhow!("Built-in templates can't be deleted"));
+    }
+    write(&templates)?;
+    info!("deleted prompt template");
+    Ok(())
+}
+
+/// Files may hold a single template or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Import {
+    One(Box<Template>),
+    Many(Vec<Template>),
+}
+
+/// Adds every template in the file at the provided path to the user's
+/// templates. Templates whose name is already taken are renamed.
+#[tracing::instrument]
+pub fn import(path: &Path) -> Result<Vec<Template>> {
+    let imported = match serde_json::from_str(&fs::read_to_string(path)?)? {
+        Import::One(template) => vec![*template],
+        Import::Many(templates) => templates,
+    };
+    let mut all = list()?;
+    let mut templates = read()?;
+    let mut added = vec![];
+    for mut template in imported {
+        template.custom = true;
+        template.name = unique_name(&template.name, &all);
+        validate(&template, &all)?;
+        all.push(template.clone());
+        templates.push(template.clone());
+        added.push(template);
+    }
+    write(&templates)?;
+    info!(count = added.len(), "imported prompt templates");
+    Ok(added)
+}
+
+/// Writes the templates with the provided names to a file that can be
+/// imported again, or all user templates if no names are provided.
+#[tracing::instrument]
+pub fn export(path: &Path, names: &[String]) -> Result<()> {
+    let templates = if names.is_empty() {
+        read()?
+    } else {
+        let all = list()?;
+        names
+            .iter()
+            .map(|name| {
+                all.iter()
+                    .find(|t| &t.name == name)
+                    .cloned()
+                    .ok_or(anyhow!("Template {} not found", name))
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+    fs::write(path, serde_json::to_string_pretty(&templates)?)?;
+    info!(count = templates.len(), "exported prompt templates");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prompt::{Template, AVAILABLE_TEMPLATES};
+    use crate::templates::{default_for, unique_name, validate, FALLBACK_TEMPLATE};
+
+    #[test]
+    fn test_validate() {
+        let mut template = AVAILABLE_TEMPLATES[0].clone();
+        assert!(validate(&template, &AVAILABLE_TEMPLATES).is_err());
+        template.name = "Mine".to_string();
+        assert!(validate(&template, &AVAILABLE_TEMPLATES).is_ok());
+        // `template` is only rendered, and checked, without roles
+        template.template = "[INST] [/INST]".to_string();
+        assert!(validate(&template, &AVAILABLE_TEMPLATES).is_ok());
+        template.roles = None;
+        assert!(validate(&template, &AVAILABLE_TEMPLATES).is_err());
+    }
+
+    #[test]
+    fn test_default_for() {
+        let template = default_for(None).unwrap();
+        assert_eq!(template.name, FALLBACK_TEMPLATE);
+        assert!(template.roles.is_none());
+        template.check().unwrap();
+    }
+
+    #[test]
+    fn test_unique_name() {
+        let mut templates: Vec<Template> = AVAILABLE_TEMPLATES.clone();
+        assert_eq!(unique_name("Mine", &templates), "Mine");
+        assert_eq!(unique_name("ChatML", &templates), "ChatML (copy)");
+        let mut copy = templates[0].clone();
+        copy.name = "ChatML (copy)".to_string();
+        templates.push(copy);
+        assert_eq!(unique_name("ChatML", &templates), "ChatML (copy 2)");
+    }
+}
@@ -0,0 +1,24 @@
+//! Fixtures shared by the tests of several modules.
+
+use crate::conversations::{Message, Role};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Returns an empty directory for the test to write to, unique to the test
+/// run so that parallel runs don't share it.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("chitchat-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn message(role: Role, content: &str) -> Message {
+    Message {
+        role,
+        content: content.to_string(),
+        stats: None,
+        seed: None,
+    }
+}
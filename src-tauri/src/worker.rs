@@ -0,0 +1,140 @@
+use crate::registry::{ModelStatus, Registry, DEFAULT_SESSION};
+use anyhow::{anyhow, Result};
+use chitchat_lib::models::ModelManager;
+use flume::{bounded, unbounded, Sender};
+use std::collections::BTreeMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use tracing::{error, info};
+
+type Job = Box<dyn FnOnce(&mut Registry) + Send>;
+
+/// What a session looked like after the last job, for the commands that only
+/// read it.
+#[derive(Clone)]
+pub struct SessionStatus {
+    pub model: Arc<dyn llm::Model>,
+    /// The number of tokens in the session's context window
+    pub used: usize,
+}
+
+/// The registry as of the last job that ran, which commands that only read
+/// it use rather than waiting behind whatever is queued.
+#[derive(Clone, Default)]
+pub struct Status {
+    pub models: Vec<ModelStatus>,
+    pub sessions: BTreeMap<String, SessionStatus>,
+}
+
+impl Status {
+    fn of(registry: &Registry) -> Self {
+        Self {
+            models: registry.list(),
+            sessions: registry
+                .sessions()
+                .map(|(id, manager)| {
+                    let status = SessionStatus {
+                        model: manager.model.clone(),
+                        used: manager.session.n_past,
+                    };
+                    (id.clone(), status)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_loaded(&self, id: &str) -> bool {
+        self.models.iter().any(|m| m.id == id)
+    }
+
+    /// Returns the provided session, or the default one, failing if it
+    /// hasn't been started.
+    pub fn session(&self, session: Option<&str>) -> Result<&SessionStatus> {
+        self.sessions
+            .get(session.unwrap_or(DEFAULT_SESSION))
+            .ok_or(anyhow!("Model not started"))
+    }
+}
+
+/// Owns the loaded models and their sessions on a thread of its own. Anything
+/// that needs them is queued here and run one job at a time, so loading a
+/// model or generating a response never blocks the async runtime or the
+/// commands that don't touch the models. Commands that only read the registry
+/// use its [`Status`] instead, which is updated after every job.
+#[derive(Clone)]
+pub struct Worker {
+    jobs: Sender<Job>,
+    status: Arc<RwLock<Status>>,
+}
+
+impl Worker {
+    pub fn spawn(budget: Option<u64>) -> Self {
+        let (jobs, rx) = unbounded::<Job>();
+        let status = Arc::new(RwLock::new(Status::default()));
+        let shared = status.clone();
+        thread::Builder::new()
+            .name("inference".to_string())
+            .spawn(move || {
+                let mut registry = Registry::new(budget);
+                for job in rx.iter() {
+                    // A panicking job may have left a session half-way through
+                    // an update, so everything is unloaded rather than taking
+                    // the whole worker down with it.
+                    if catch_unwind(AssertUnwindSafe(|| job(&mut registry))).is_err() {
+                        error!("inference job panicked, unloading models");
+                        registry = Registry::new(registry.budget);
+                    }
+                    *shared.write().unwrap() = Status::of(&registry);
+                }
+                info!("inference worker stopped");
+            })
+            .expect("spawning inference worker");
+        Self { jobs, status }
+    }
+
+    /// The registry as of the last job that ran.
+    pub fn status(&self) -> Status {
+        self.status.read().unwrap().clone()
+    }
+
+    /// Queues the job behind any others without waiting for it to run.
+    pub fn queue<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce(&mut Registry) + Send + 'static,
+    {
+        self.jobs
+            .send(Box::new(job))
+            .map_err(|_| anyhow!("Inference worker has stopped"))
+    }
+
+    /// Queues the job behind any others and waits for its result.
+    pub async fn run<T, F>(&self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Registry) -> T + Send + 'static,
+    {
+        let (tx, rx) = bounded(1);
+        self.queue(move |manager| {
+            let _ = tx.send(job(manager));
+        })?;
+        rx.recv_async()
+            .await
+            .map_err(|_| anyhow!("Inference job failed"))
+    }
+
+    /// Runs the job against the provided session, or the default one,
+    /// failing if it hasn't been started.
+    pub async fn with_session<T, F>(&self, session: Option<String>, job: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut ModelManager) -> Result<T, String> + Send + 'static,
+    {
+        self.run(move |registry| {
+            let id = session.as_deref().unwrap_or(DEFAULT_SESSION);
+            job(registry.session(id).map_err(|err| err.to_string())?)
+        })
+        .await
+        .map_err(|err| err.to_string())?
+    }
+}
@@ -46,6 +46,8 @@ const schema = yup.object({
   prompt: yup.object({
     template: yup.string().required(),
     warmup: yup.string(),
+    stop: yup.array().of(yup.string()),
+    roles: yup.object().nullable(),
   }),
   contextFiles: yup.array().of(yup.string()),
 });
@@ -80,6 +82,14 @@ export default function Sidebar({
     return models.find((m) => m.filename === selectedFilename)?.custom;
   }, [selectedFilename]);
 
+  // Pre-select the architecture that was detected from the model file
+  useEffect(() => {
+    const model = getModelFromFilename(selectedFilename);
+    if (model?.architecture) setValue("architecture", model.architecture);
+    if (templates.some((t) => t.name == model?.template))
+      setValue("prompt.name", model.template);
+  }, [selectedFilename]);
+
   const [progress, setProgress] = useState(null);
 
   function handleStart(data) {
@@ -113,6 +123,8 @@ export default function Sidebar({
     if (template) {
       setValue("prompt.warmup", template.warmup);
       setValue("prompt.template", template.template);
+      setValue("prompt.stop", template.stop ?? []);
+      setValue("prompt.roles", template.roles ?? null);
     }
   }, [w]);
 
@@ -1,14 +1,65 @@
 import { invoke } from "@tauri-apps/api/tauri";
 import { listen } from "@tauri-apps/api/event";
 
-export async function prompt(message, onToken) {
+export async function prompt(message, onToken, sampling, maxTokens, seed) {
+  return await streamResponse("prompt", onToken, {
+    message,
+    sampling,
+    maxTokens,
+    seed,
+  });
+}
+
+export async function regenerate(onToken, sampling, maxTokens, seed) {
+  return await streamResponse("regenerate", onToken, {
+    sampling,
+    maxTokens,
+    seed,
+  });
+}
+
+export async function editLastMessage(
+  message,
+  onToken,
+  sampling,
+  maxTokens,
+  seed,
+) {
+  return await streamResponse("edit_last_message", onToken, {
+    message,
+    sampling,
+    maxTokens,
+    seed,
+  });
+}
+
+export async function undo() {
+  return await invoke("undo");
+}
+
+export async function listSiblings(exchange) {
+  return await invoke("list_siblings", { exchange });
+}
+
+export async function switchBranch(exchange) {
+  return await invoke("switch_branch", { exchange });
+}
+
+export async function fork(exchange = null) {
+  return await invoke("fork", { exchange });
+}
+
+async function streamResponse(command, onToken, args) {
   let stop = () => {};
   stop = await listen("prompt_response", (event) => {
     if (event.payload?.message?.length > 0) onToken(event.payload.message);
     console.debug(event);
   });
-  await invoke("prompt", { message });
-  stop();
+  try {
+    return await invoke(command, args);
+  } finally {
+    stop();
+  }
 }
 
 export async function getModels() {
@@ -23,6 +74,158 @@ export async function getPromptTemplates() {
   return await invoke("get_prompt_templates");
 }
 
-export async function cancel() {
-  return await invoke("cancel");
+export async function createPromptTemplate(template) {
+  return await invoke("create_prompt_template", { template });
+}
+
+export async function updatePromptTemplate(name, template) {
+  return await invoke("update_prompt_template", { name, template });
+}
+
+export async function duplicatePromptTemplate(name) {
+  return await invoke("duplicate_prompt_template", { name });
+}
+
+export async function deletePromptTemplate(name) {
+  return await invoke("delete_prompt_template", { name });
+}
+
+export async function importPromptTemplates(path) {
+  return await invoke("import_prompt_templates", { path });
+}
+
+export async function exportPromptTemplates(path, names = []) {
+  return await invoke("export_prompt_templates", { path, names });
+}
+
+export async function countTokens(text) {
+  return await invoke("count_tokens", { text });
+}
+
+export async function onContextFileTokens(callback) {
+  return await listen("context_file_tokens", (event) =>
+    callback(event.payload),
+  );
+}
+
+export async function cancel(job = null) {
+  return await invoke("cancel", { job });
+}
+
+export async function listDownloads() {
+  return await invoke("list_downloads");
+}
+
+export async function enqueueDownload(filename) {
+  return await invoke("enqueue_download", { filename });
+}
+
+export async function pauseDownload(filename) {
+  return await invoke("pause_download", { filename });
+}
+
+export async function resumeDownload(filename) {
+  return await invoke("resume_download", { filename });
+}
+
+export async function removeDownload(filename) {
+  return await invoke("remove_download", { filename });
+}
+
+export async function onDownloadProgress(callback) {
+  return await listen("download_progress", (event) => callback(event.payload));
+}
+
+export async function onContextOverflow(callback) {
+  return await listen("context_overflow", (event) => callback(event.payload));
+}
+
+export async function getDiskUsage() {
+  return await invoke("get_disk_usage");
+}
+
+export async function deleteModel(filename) {
+  return await invoke("delete_model", { filename });
+}
+
+export async function inspectModel(filename) {
+  return await invoke("inspect_model", { filename });
+}
+
+export async function getModelMetadata(filename) {
+  return await invoke("get_model_metadata", { filename });
+}
+
+export async function saveModelMetadata(filename, metadata) {
+  return await invoke("save_model_metadata", { filename, metadata });
+}
+
+export async function getSamplingPresets() {
+  return await invoke("get_sampling_presets");
+}
+
+export async function listConversations() {
+  return await invoke("list_conversations");
+}
+
+export async function openConversation(id) {
+  return await invoke("open_conversation", { id });
+}
+
+export async function renameConversation(id, title) {
+  return await invoke("rename_conversation", { id, title });
+}
+
+export async function deleteConversation(id) {
+  return await invoke("delete_conversation", { id });
+}
+
+export async function restoreSnapshot(id) {
+  return await invoke("restore_snapshot", { id });
+}
+
+export async function listCacheEntries() {
+  return await invoke("list_cache_entries");
+}
+
+export async function listSnapshots() {
+  return await invoke("list_snapshots");
+}
+
+export async function clearCache() {
+  return await invoke("clear_cache");
+}
+
+export async function loadModel(options) {
+  return await invoke("load_model", options);
+}
+
+export async function unloadModel(id) {
+  return await invoke("unload_model", { id });
+}
+
+export async function listLoadedModels() {
+  return await invoke("list_loaded_models");
+}
+
+export async function closeSession(session) {
+  return await invoke("close_session", { session });
+}
+
+export async function getSettings() {
+  return await invoke("get_settings");
+}
+
+export async function saveSettings(settings) {
+  return await invoke("save_settings", { settings });
+}
+
+export async function compareModels(options) {
+  return await invoke("compare_models", options);
+}
+
+export async function onComparisonResponse(callback) {
+  return await listen("comparison_response", (event) =>
+    callback(event.payload),
+  );
 }
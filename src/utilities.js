@@ -17,9 +17,9 @@ export function useError() {
 export function useCancellation() {
   const dispatch = useDispatch();
   const [setError] = useError();
-  return () => {
+  return (job = null) => {
     dispatch(setCancelling(true));
-    cancel()
+    cancel(job)
       .then()
       .catch(setError)
       .finally(() => dispatch(setCancelling(false)));